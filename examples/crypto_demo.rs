@@ -0,0 +1,89 @@
+// examples/crypto_demo.rs
+// Interactive encrypt/decrypt demo of the crypto module: `cargo run --example crypto_demo`
+use noid_messenger::crypto::{generate_keypair, identity, receiver, sender, KemVersion};
+use std::io::{self, Write};
+
+fn main() {
+    println!("=== Noid Kyber + AES-GCM Interactive Demo ===\n");
+    
+    // Generate keypair for receiver
+    println!("🔑 Generating Kyber1024 keypair...");
+    let (pk, sk) = generate_keypair::generate_keypair();
+    println!("✓ Keypair generated!");
+    println!("  - Public key length: {} bytes", pk.len());
+    println!("  - Secret key length: {} bytes\n", sk.len());
+
+    // Identity (signing) keypair for the sender
+    println!("🔑 Generating ML-DSA-65 identity keypair...");
+    let (sender_vk, sender_seed) = identity::generate_identity();
+    println!("✓ Identity generated! Verifying key length: {} bytes\n", sender_vk.len());
+    
+    loop {
+        // Get user input
+        print("💬 Enter message to encrypt (or 'quit' to exit): ");
+        
+        let mut input = String::new();
+        match io::stdin().read_line(&mut input) {
+            Ok(_) => {
+                let message = input.trim();
+                
+                if message.is_empty() {
+                    continue;
+                }
+                
+                if message.to_lowercase() == "quit" {
+                    println!("👋 Goodbye!");
+                    break;
+                }
+                
+                // Encrypt the message
+                println!("\n🔒 Encrypting message...");
+                let envelope = match sender::send_message(message.as_bytes(), &pk, KemVersion::Kyber1024, "demo-sender", "demo-receiver", &sender_seed) {
+                    Ok(envelope) => envelope,
+                    Err(e) => {
+                        println!("❌ Encryption failed: {}\n", e);
+                        continue;
+                    }
+                };
+                
+                println!("✓ Encryption complete!");
+                println!("  - Original message: \"{}\"", message);
+                println!("  - AES ciphertext length: {} bytes", envelope.ciphertext.len());
+                println!("  - Nonce length: {} bytes", envelope.nonce.len());
+                println!("  - Kyber ciphertext length: {} bytes", envelope.kem_ct.len());
+                println!("  - Signature length: {} bytes", envelope.signature.len());
+                
+                // Decrypt the message
+                println!("\n🔓 Decrypting message...");
+                match receiver::receive_message(&envelope, &sk, &sender_vk, "demo-sender", "demo-receiver") {
+                    Ok(decrypted) => {
+                        let decrypted_text = String::from_utf8_lossy(&decrypted);
+                        println!("✓ Decryption successful!");
+                        println!("  - Decrypted message: \"{}\"", decrypted_text);
+                        
+                        // Verify the messages match
+                        if message == decrypted_text {
+                            println!("✅ Messages match perfectly!\n");
+                        } else {
+                            println!("❌ Warning: Messages don't match!\n");
+                        }
+                    }
+                    Err(e) => {
+                        println!("❌ Decryption failed: {}\n", e);
+                    }
+                }
+                
+                println!("{}", "─".repeat(50));
+            }
+            Err(error) => {
+                println!("❌ Error reading input: {}", error);
+                break;
+            }
+        }
+    }
+}
+
+fn print(msg: &str) {
+    print!("{}", msg);
+    io::stdout().flush().unwrap();
+}
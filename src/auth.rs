@@ -1,41 +1,341 @@
 // src/auth.rs
 use once_cell::sync::Lazy;
-use std::collections::HashMap;
+use rusqlite::{params, OptionalExtension};
 use uuid::Uuid;
-use crate::state::TokenMap;
-
-/// Hardcoded user database (username -> bcrypt hash)
-/// Passwords: alice123, bob123 (for demo). In production, never commit plaintext!
-static USERS: Lazy<HashMap<&'static str, &'static str>> = Lazy::new(|| {
-    let mut m = HashMap::new();
-    // These are bcrypt hashes of "alice123" and "bob123" created once.
-    // If you want to recreate, run hash("alice123", DEFAULT_COST) locally
-    m.insert("alice", "$2b$12$GXs2fE4sU7o4n0mQJm7K0u8sYf2lq7H2YZy7wT6oH0vQY9FJ0PR1y"); // EXAMPLE placeholder
-    m.insert("bob", "$2b$12$XKq1f8R8q0k6YvI6Oa9aYe1G2nYk7s2tL5Q9j8ZpV5fL3cQ1J5kIa");   // EXAMPLE placeholder
-    m
+use axum::http::{HeaderMap, header::AUTHORIZATION};
+use crate::db::{self, Db};
+use sha2::{Digest, Sha256};
+
+/// Minimum password length accepted by /register
+const MIN_PASSWORD_LEN: usize = 8;
+
+/// Absolute lifetime of a session token (7 days)
+const TOKEN_TTL_SECS: i64 = 7 * 24 * 60 * 60;
+/// A token unused for this long is dead (24 hours)
+const TOKEN_IDLE_SECS: i64 = 24 * 60 * 60;
+/// How often the sweeper drops expired tokens
+const TOKEN_SWEEP_INTERVAL_SECS: u64 = 60;
+
+/// Hash checked when the username doesn't exist, so a failed login takes the
+/// same time whether or not the account is real.
+static DUMMY_HASH: Lazy<String> = Lazy::new(|| {
+    bcrypt::hash("noid-dummy-password", bcrypt::DEFAULT_COST).expect("bcrypt hash")
 });
 
-/// Verify username/password. Returns true if valid.
-pub async fn verify_login(username: &str, password: &str) -> bool {
-    // NOTE: For demo, we accept if username == "alice" && password == "alice123", etc.
-    // The hashed constants above are placeholders — it's often simpler in a hackathon
-    // to directly check plaintext for speed. Replace with real hashes in production.
+#[derive(Debug)]
+pub enum RegisterError {
+    InvalidUsername(&'static str),
+    InvalidPassword(&'static str),
+    UsernameTaken,
+    Internal(String),
+}
 
-    match username {
-        "alice" => password == "alice123",
-        "bob" => password == "bob123",
-        _ => false
+impl std::fmt::Display for RegisterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RegisterError::InvalidUsername(msg) | RegisterError::InvalidPassword(msg) => write!(f, "{}", msg),
+            RegisterError::UsernameTaken => write!(f, "username already taken"),
+            RegisterError::Internal(e) => write!(f, "internal error: {}", e),
+        }
     }
 }
 
-/// Create a session token and store it in tokens map
-pub async fn create_token_for_user(tokens: &TokenMap, username: &str) -> String {
+/// Usernames are case-insensitive; everything server-side uses the lowercase form
+pub fn normalize_username(username: &str) -> String {
+    username.trim().to_lowercase()
+}
+
+/// Same rules as the login form: 3–20 chars, letters/numbers/space/_ . -
+fn validate_username(username: &str) -> Result<(), RegisterError> {
+    let len = username.chars().count();
+    if len < 3 {
+        return Err(RegisterError::InvalidUsername("username must be at least 3 characters"));
+    }
+    if len > 20 {
+        return Err(RegisterError::InvalidUsername("username must be at most 20 characters"));
+    }
+    if !username.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | ' ' | '.' | '-')) {
+        return Err(RegisterError::InvalidUsername("use only letters, numbers, spaces, _ . -"));
+    }
+    Ok(())
+}
+
+fn validate_password(password: &str) -> Result<(), RegisterError> {
+    if password.chars().count() < MIN_PASSWORD_LEN {
+        return Err(RegisterError::InvalidPassword("password must be at least 8 characters"));
+    }
+    Ok(())
+}
+
+/// Create a new account with a bcrypt-hashed password. Returns the normalized username.
+pub async fn register_user(db: &Db, username: &str, password: &str) -> Result<String, RegisterError> {
+    let username = normalize_username(username);
+    validate_username(&username)?;
+    validate_password(password)?;
+
+    // bcrypt is deliberately slow; keep it off the async workers
+    let password = password.to_string();
+    let hash = tokio::task::spawn_blocking(move || bcrypt::hash(password, bcrypt::DEFAULT_COST))
+        .await
+        .map_err(|e| RegisterError::Internal(e.to_string()))?
+        .map_err(|e| RegisterError::Internal(e.to_string()))?;
+
+    let conn = db.lock().unwrap();
+    let inserted = conn
+        .execute(
+            "INSERT OR IGNORE INTO users (username, password_hash, created_at) VALUES (?1, ?2, ?3)",
+            params![username, hash, db::unix_now()],
+        )
+        .map_err(|e| RegisterError::Internal(e.to_string()))?;
+    if inserted == 0 {
+        return Err(RegisterError::UsernameTaken);
+    }
+    Ok(username)
+}
+
+/// Returns true if an account with this username exists
+pub fn user_exists(db: &Db, username: &str) -> bool {
+    let conn = db.lock().unwrap();
+    conn.query_row(
+        "SELECT 1 FROM users WHERE username = ?1",
+        params![normalize_username(username)],
+        |_| Ok(()),
+    )
+    .optional()
+    .map(|found| found.is_some())
+    .unwrap_or(false)
+}
+
+/// Verify username/password against the stored bcrypt hash. Returns true if valid.
+pub async fn verify_login(db: &Db, username: &str, password: &str) -> bool {
+    let username = normalize_username(username);
+    let stored: Option<String> = {
+        let conn = db.lock().unwrap();
+        match conn
+            .query_row(
+                "SELECT password_hash FROM users WHERE username = ?1",
+                params![username],
+                |row| row.get(0),
+            )
+            .optional()
+        {
+            Ok(h) => h,
+            Err(e) => {
+                println!("[AUTH] User lookup failed for '{}': {}", username, e);
+                None
+            }
+        }
+    };
+
+    let password = password.to_string();
+    tokio::task::spawn_blocking(move || {
+        let hash = stored.as_deref().unwrap_or(DUMMY_HASH.as_str());
+        let ok = bcrypt::verify(password, hash).unwrap_or(false);
+        ok && stored.is_some()
+    })
+    .await
+    .unwrap_or(false)
+}
+
+/// Pull the token out of an `Authorization: Bearer <token>` header
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+}
+
+/// Tokens are stored as SHA-256 hex so a leaked database can't be replayed
+fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Resolve the `Authorization: Bearer` header to a username (if the token is live)
+pub async fn user_from_headers(db: &Db, headers: &HeaderMap) -> Option<String> {
+    username_for_token(db, bearer_token(headers)?).await
+}
+
+fn is_expired(created_at: i64, last_seen: i64, now: i64) -> bool {
+    now - created_at > TOKEN_TTL_SECS || now - last_seen > TOKEN_IDLE_SECS
+}
+
+/// Create a session token and persist its hash. Fails if the session can't be
+/// stored, since such a token could never authenticate.
+pub async fn create_token_for_user(db: &Db, username: &str) -> rusqlite::Result<String> {
     let token = Uuid::new_v4().to_string();
-    tokens.lock().await.insert(token.clone(), username.to_string());
-    token
+    let now = db::unix_now();
+    let conn = db.lock().unwrap();
+    conn.execute(
+        "INSERT INTO sessions (token_hash, username, created_at, last_seen) VALUES (?1, ?2, ?3, ?3)",
+        params![hash_token(&token), username, now],
+    )
+    .inspect_err(|e| println!("[AUTH] Failed to store session for '{}': {}", username, e))?;
+    Ok(token)
+}
+
+/// Validate token and return username (if any). Expired tokens are dropped,
+/// live ones get their idle timer reset.
+pub async fn username_for_token(db: &Db, token: &str) -> Option<String> {
+    let now = db::unix_now();
+    let token_hash = hash_token(token);
+    let conn = db.lock().unwrap();
+    let (username, created_at, last_seen): (String, i64, i64) = conn
+        .query_row(
+            "SELECT username, created_at, last_seen FROM sessions WHERE token_hash = ?1",
+            params![token_hash],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .optional()
+        .ok()??;
+    if is_expired(created_at, last_seen, now) {
+        let _ = conn.execute("DELETE FROM sessions WHERE token_hash = ?1", params![token_hash]);
+        return None;
+    }
+    let _ = conn.execute(
+        "UPDATE sessions SET last_seen = ?1 WHERE token_hash = ?2",
+        params![now, token_hash],
+    );
+    Some(username)
+}
+
+/// Revoke a token. Returns the username it belonged to (if it was live).
+pub async fn revoke_token(db: &Db, token: &str) -> Option<String> {
+    let username = username_for_token(db, token).await?;
+    let conn = db.lock().unwrap();
+    conn.execute("DELETE FROM sessions WHERE token_hash = ?1", params![hash_token(token)]).ok()?;
+    Some(username)
+}
+
+/// Swap a live token for a fresh one; the old token stops working. The new token
+/// keeps the session's original `created_at`, so refreshing resets the idle timer
+/// but never extends the session past `TOKEN_TTL_SECS`. `Ok(None)` if the token
+/// isn't live; an error (with the old token left as it was) if the database fails.
+pub async fn refresh_token(db: &Db, token: &str) -> rusqlite::Result<Option<String>> {
+    let now = db::unix_now();
+    let old_hash = hash_token(token);
+    let new_token = Uuid::new_v4().to_string();
+    let mut conn = db.lock().unwrap();
+    let tx = conn.transaction()?;
+    let Some((username, created_at, last_seen)) = tx
+        .query_row(
+            "SELECT username, created_at, last_seen FROM sessions WHERE token_hash = ?1",
+            params![old_hash],
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?, row.get::<_, i64>(2)?)),
+        )
+        .optional()?
+    else {
+        return Ok(None);
+    };
+    tx.execute("DELETE FROM sessions WHERE token_hash = ?1", params![old_hash])?;
+    if is_expired(created_at, last_seen, now) {
+        tx.commit()?;
+        return Ok(None);
+    }
+    tx.execute(
+        "INSERT INTO sessions (token_hash, username, created_at, last_seen) VALUES (?1, ?2, ?3, ?4)",
+        params![hash_token(&new_token), username, created_at, now],
+    )
+    .inspect_err(|e| println!("[AUTH] Failed to store refreshed session for '{}': {}", username, e))?;
+    tx.commit()?;
+    Ok(Some(new_token))
 }
 
-/// Validate token and return username (if any)
-pub async fn username_for_token(tokens: &TokenMap, token: &str) -> Option<String> {
-    tokens.lock().await.get(token).cloned()
+/// Periodically drop expired sessions so the table doesn't grow forever
+pub fn spawn_token_sweeper(db: Db) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(TOKEN_SWEEP_INTERVAL_SECS));
+        loop {
+            interval.tick().await;
+            let now = db::unix_now();
+            let conn = db.lock().unwrap();
+            match conn.execute(
+                "DELETE FROM sessions WHERE created_at < ?1 OR last_seen < ?2",
+                params![now - TOKEN_TTL_SECS, now - TOKEN_IDLE_SECS],
+            ) {
+                Ok(0) => {}
+                Ok(removed) => println!("[AUTH] Swept {} expired token(s)", removed),
+                Err(e) => println!("[AUTH] Token sweep failed: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn created_at(db: &Db, token: &str) -> Option<i64> {
+        let conn = db.lock().unwrap();
+        conn.query_row("SELECT created_at FROM sessions WHERE token_hash = ?1", params![hash_token(token)], |row| row.get(0))
+            .optional()
+            .unwrap()
+    }
+
+    fn age_session(db: &Db, token: &str, secs: i64) {
+        let conn = db.lock().unwrap();
+        conn.execute("UPDATE sessions SET created_at = created_at - ?1 WHERE token_hash = ?2", params![secs, hash_token(token)])
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn tokens_expire_after_the_ttl_or_when_idle() {
+        let db = db::open(":memory:").unwrap();
+        let token = create_token_for_user(&db, "alice").await.unwrap();
+        age_session(&db, &token, TOKEN_TTL_SECS - 60);
+        assert_eq!(username_for_token(&db, &token).await.as_deref(), Some("alice"));
+        age_session(&db, &token, 120);
+        assert_eq!(username_for_token(&db, &token).await, None);
+        // The expired session was dropped, not just refused
+        assert_eq!(created_at(&db, &token), None);
+
+        let idle = create_token_for_user(&db, "alice").await.unwrap();
+        db.lock()
+            .unwrap()
+            .execute("UPDATE sessions SET last_seen = last_seen - ?1 WHERE token_hash = ?2", params![TOKEN_IDLE_SECS + 1, hash_token(&idle)])
+            .unwrap();
+        assert_eq!(username_for_token(&db, &idle).await, None);
+    }
+
+    #[tokio::test]
+    async fn revoked_tokens_stop_working() {
+        let db = db::open(":memory:").unwrap();
+        let token = create_token_for_user(&db, "alice").await.unwrap();
+        let other = create_token_for_user(&db, "alice").await.unwrap();
+        assert_eq!(revoke_token(&db, &token).await.as_deref(), Some("alice"));
+        assert_eq!(username_for_token(&db, &token).await, None);
+        assert_eq!(revoke_token(&db, &token).await, None);
+        assert_eq!(refresh_token(&db, &token).await.unwrap(), None);
+        // Other sessions of the same user are untouched
+        assert_eq!(username_for_token(&db, &other).await.as_deref(), Some("alice"));
+    }
+
+    #[tokio::test]
+    async fn refresh_keeps_the_session_start() {
+        let db = db::open(":memory:").unwrap();
+        let token = create_token_for_user(&db, "alice").await.unwrap();
+        age_session(&db, &token, TOKEN_TTL_SECS - 60);
+        let started = created_at(&db, &token).unwrap();
+
+        let refreshed = refresh_token(&db, &token).await.unwrap().unwrap();
+        assert_eq!(created_at(&db, &refreshed), Some(started));
+        assert_eq!(username_for_token(&db, &token).await, None);
+        assert_eq!(username_for_token(&db, &refreshed).await.as_deref(), Some("alice"));
+
+        // Past the absolute TTL a refresh is refused and the session is gone
+        age_session(&db, &refreshed, 120);
+        assert_eq!(refresh_token(&db, &refreshed).await.unwrap(), None);
+        assert_eq!(created_at(&db, &refreshed), None);
+    }
+
+    #[tokio::test]
+    async fn token_creation_fails_when_the_session_cant_be_stored() {
+        let db = db::open(":memory:").unwrap();
+        let token = create_token_for_user(&db, "alice").await.unwrap();
+        db.lock().unwrap().execute_batch("DROP TABLE sessions").unwrap();
+        assert!(create_token_for_user(&db, "alice").await.is_err());
+        assert!(refresh_token(&db, &token).await.is_err());
+    }
 }
@@ -0,0 +1,25 @@
+use crate::crypto::{kdf, KemVersion};
+
+/// A pairwise message as made by `sender::send_message`
+#[derive(Debug, Clone)]
+pub struct Envelope {
+    pub kem: KemVersion,
+    pub kem_ct: Vec<u8>,
+    pub nonce: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+    /// Sender's identity signature (ML-DSA-65) over header + ciphertext
+    pub signature: Vec<u8>,
+}
+
+impl Envelope {
+    /// What the signature covers: the header (message context: format version,
+    /// KEM, sender, recipient, KEM ciphertext; then the nonce) and the ciphertext
+    pub(crate) fn signed_bytes(&self, sender_id: &str, recipient_id: &str) -> Vec<u8> {
+        let mut out = kdf::message_context(sender_id, recipient_id, self.kem, &self.kem_ct);
+        for part in [&self.nonce, &self.ciphertext] {
+            out.extend_from_slice(&(part.len() as u32).to_be_bytes());
+            out.extend_from_slice(part);
+        }
+        out
+    }
+}
@@ -0,0 +1,38 @@
+/// Everything the crypto API can fail with. Malformed input from the network
+/// comes back as one of these rather than a panic, so a bad frame can't take
+/// down the task handling it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CryptoError {
+    /// A public or secret key has the wrong length or encoding
+    BadKeyLength,
+    /// A KEM ciphertext, nonce or other encoded field is malformed
+    BadCiphertext,
+    /// AEAD decryption failed: wrong key, tampered data or a different context
+    AuthFailed,
+    /// The signature doesn't verify against the sender's identity key
+    BadSignature,
+    /// The signing library refused to sign (it only does so for an over-long
+    /// FIPS 204 context string)
+    SigningFailed,
+    /// No key for this message: unknown key id or chain, a message key that was
+    /// already used, or one too far ahead of the chain
+    NoKey,
+    /// Plaintext too long for AES-GCM
+    MessageTooLong,
+}
+
+impl std::fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CryptoError::BadKeyLength => write!(f, "bad key length"),
+            CryptoError::BadCiphertext => write!(f, "malformed ciphertext"),
+            CryptoError::AuthFailed => write!(f, "decryption failed"),
+            CryptoError::BadSignature => write!(f, "signature verification failed"),
+            CryptoError::SigningFailed => write!(f, "signing failed"),
+            CryptoError::NoKey => write!(f, "no key for this message"),
+            CryptoError::MessageTooLong => write!(f, "message too long"),
+        }
+    }
+}
+
+impl std::error::Error for CryptoError {}
@@ -0,0 +1,137 @@
+//! Hybrid X25519 + Kyber1024 KEM. The shared secret is HKDF-SHA256 over both
+//! component secrets, bound to the ciphertext and the recipient's key, so it
+//! stays secret as long as either X25519 or Kyber holds.
+//!
+//! Keys and ciphertexts are the X25519 part followed by the Kyber part.
+
+use aes_gcm::aead::OsRng;
+use hkdf::Hkdf;
+use pqcrypto_kyber::kyber1024;
+use sha2::{Digest, Sha256};
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+use crate::crypto::{generate_keypair, kyber, CryptoError};
+
+/// Length of an X25519 public or secret key
+pub const X25519_KEY_LEN: usize = 32;
+
+/// HKDF info prefix; the rest of the info is a hash of the ephemeral key, the
+/// recipient's X25519 key and the Kyber ciphertext
+const HYBRID_INFO: &[u8] = b"noid-hybrid-kem-v1";
+
+pub fn public_key_bytes() -> usize {
+    X25519_KEY_LEN + kyber1024::public_key_bytes()
+}
+
+pub fn secret_key_bytes() -> usize {
+    X25519_KEY_LEN + kyber1024::secret_key_bytes()
+}
+
+pub fn ciphertext_bytes() -> usize {
+    X25519_KEY_LEN + kyber1024::ciphertext_bytes()
+}
+
+/// Generate a hybrid keypair, returned as (public key, secret key)
+pub fn generate_keypair() -> (Vec<u8>, Vec<u8>) {
+    let x_sk = StaticSecret::random_from_rng(OsRng);
+    let x_pk = PublicKey::from(&x_sk);
+    let (k_pk, k_sk) = generate_keypair::generate_keypair();
+    ([x_pk.as_bytes().as_slice(), &k_pk].concat(), [x_sk.as_bytes().as_slice(), &k_sk].concat())
+}
+
+/// Encapsulate to a hybrid public key.
+/// Returns (ciphertext, shared_secret)
+pub fn encapsulate(pk_bytes: &[u8]) -> Result<(Vec<u8>, Vec<u8>), CryptoError> {
+    if pk_bytes.len() != public_key_bytes() {
+        return Err(CryptoError::BadKeyLength);
+    }
+    let (x_pk, k_pk) = pk_bytes.split_at(X25519_KEY_LEN);
+    let x_pk: [u8; X25519_KEY_LEN] = x_pk.try_into().map_err(|_| CryptoError::BadKeyLength)?;
+
+    let ephemeral = EphemeralSecret::random_from_rng(OsRng);
+    let epk = PublicKey::from(&ephemeral);
+    let x_ss = ephemeral.diffie_hellman(&PublicKey::from(x_pk));
+    let (kyber_ct, k_ss) = kyber::encapsulate(k_pk)?;
+
+    let shared_secret = combine(x_ss.as_bytes(), &k_ss, epk.as_bytes(), &x_pk, &kyber_ct);
+    Ok(([epk.as_bytes().as_slice(), &kyber_ct].concat(), shared_secret))
+}
+
+/// Decapsulate a hybrid ciphertext with the recipient's hybrid secret key
+pub fn decapsulate(ct_bytes: &[u8], sk_bytes: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    if ct_bytes.len() != ciphertext_bytes() {
+        return Err(CryptoError::BadCiphertext);
+    }
+    if sk_bytes.len() != secret_key_bytes() {
+        return Err(CryptoError::BadKeyLength);
+    }
+    let (epk, kyber_ct) = ct_bytes.split_at(X25519_KEY_LEN);
+    let (x_sk, k_sk) = sk_bytes.split_at(X25519_KEY_LEN);
+    let epk: [u8; X25519_KEY_LEN] = epk.try_into().map_err(|_| CryptoError::BadCiphertext)?;
+    let x_sk: [u8; X25519_KEY_LEN] = x_sk.try_into().map_err(|_| CryptoError::BadKeyLength)?;
+
+    let x_sk = StaticSecret::from(x_sk);
+    let x_pk = PublicKey::from(&x_sk);
+    let x_ss = x_sk.diffie_hellman(&PublicKey::from(epk));
+    let k_ss = kyber::decapsulate(kyber_ct, k_sk)?;
+
+    Ok(combine(x_ss.as_bytes(), &k_ss, &epk, x_pk.as_bytes(), kyber_ct))
+}
+
+/// HKDF-SHA256(ikm = X25519 secret || Kyber secret, info = label ||
+/// SHA-256(epk || recipient X25519 key || Kyber ciphertext)). The transcript is
+/// hashed because WebCrypto implementations cap HKDF info at 1024 bytes.
+fn combine(x_ss: &[u8], k_ss: &[u8], epk: &[u8], x_pk: &[u8], kyber_ct: &[u8]) -> Vec<u8> {
+    let ikm = [x_ss, k_ss].concat();
+    let transcript = Sha256::new().chain_update(epk).chain_update(x_pk).chain_update(kyber_ct).finalize();
+    let info = [HYBRID_INFO, transcript.as_slice()].concat();
+    let mut shared_secret = vec![0u8; 32];
+    Hkdf::<Sha256>::new(None, &ikm)
+        .expand(&info, &mut shared_secret)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    shared_secret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let (pk, sk) = generate_keypair();
+        assert_eq!((pk.len(), sk.len()), (public_key_bytes(), secret_key_bytes()));
+        let (ct, ss) = encapsulate(&pk).unwrap();
+        assert_eq!((ct.len(), ss.len()), (ciphertext_bytes(), 32));
+        assert_eq!(decapsulate(&ct, &sk).unwrap(), ss);
+
+        // Every encapsulation is fresh, and only the recipient's key opens it
+        let (ct2, ss2) = encapsulate(&pk).unwrap();
+        assert_ne!((ct2, ss2), (ct.clone(), ss.clone()));
+        let (_, other_sk) = generate_keypair();
+        assert_ne!(decapsulate(&ct, &other_sk).unwrap(), ss);
+    }
+
+    #[test]
+    fn either_half_changing_changes_the_secret() {
+        let (pk, sk) = generate_keypair();
+        let (ct, ss) = encapsulate(&pk).unwrap();
+        for i in [0, X25519_KEY_LEN + 5] {
+            let mut tampered = ct.clone();
+            tampered[i] ^= 1;
+            assert_ne!(decapsulate(&tampered, &sk).unwrap(), ss, "byte {}", i);
+        }
+    }
+
+    #[test]
+    fn rejects_wrong_lengths() {
+        let (pk, sk) = generate_keypair();
+        let (ct, _) = encapsulate(&pk).unwrap();
+        for bad_pk in [&pk[..pk.len() - 1], &pk[X25519_KEY_LEN..], &[pk.as_slice(), &[0]].concat()[..]] {
+            assert!(matches!(encapsulate(bad_pk), Err(CryptoError::BadKeyLength)));
+        }
+        for bad_ct in [&ct[..ct.len() - 1], &ct[X25519_KEY_LEN..], &[ct.as_slice(), &[0]].concat()[..]] {
+            assert!(matches!(decapsulate(bad_ct, &sk), Err(CryptoError::BadCiphertext)));
+        }
+        assert!(matches!(decapsulate(&ct, &sk[1..]), Err(CryptoError::BadKeyLength)));
+    }
+}
@@ -0,0 +1,90 @@
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::crypto::KemVersion;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// HMAC-SHA256 of `data` under `key`
+pub fn hmac(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+/// One step of a symmetric KDF chain (HMAC-SHA256, as in Signal's chains):
+/// returns (next chain key, message key). The old chain key can't be recovered
+/// from either output, so deleting it gives forward secrecy within the chain.
+pub fn chain_step(chain_key: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    (hmac(chain_key, &[0x02]), hmac(chain_key, &[0x01]))
+}
+
+/// Format version of pairwise messages; version 1 used the raw shared secret as
+/// the AES key, version 2 put the whole KEM ciphertext into the context
+pub const MESSAGE_VERSION: u8 = 3;
+
+/// Everything a pairwise message is bound to: format version, KEM, sender,
+/// recipient and KEM ciphertext. Used as the HKDF info for the message key and
+/// as the AES-GCM associated data, so a ciphertext lifted into another
+/// conversation (or re-labelled with another KEM) neither decrypts nor verifies.
+/// The browser wraps its per-device content keys under the same context; the
+/// KEM ciphertext goes in hashed because WebCrypto caps HKDF info at 1024 bytes.
+pub fn message_context(sender: &str, recipient: &str, kem: KemVersion, kem_ct: &[u8]) -> Vec<u8> {
+    let mut context = b"noid-message".to_vec();
+    context.push(MESSAGE_VERSION);
+    context.push(kem as u8);
+    for part in [sender.as_bytes(), recipient.as_bytes(), Sha256::digest(kem_ct).as_slice()] {
+        context.extend_from_slice(&(part.len() as u32).to_be_bytes());
+        context.extend_from_slice(part);
+    }
+    context
+}
+
+/// AES-256 key for a pairwise message: HKDF-SHA256 of the KEM shared secret with
+/// the message context as info
+pub fn message_key(shared_secret: &[u8], context: &[u8]) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(None, shared_secret)
+        .expand(context, &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn context_binds_every_field() {
+        let base = message_context("alice", "bob", KemVersion::Kyber1024, b"ct");
+        for other in [
+            message_context("carol", "bob", KemVersion::Kyber1024, b"ct"),
+            message_context("alice", "carol", KemVersion::Kyber1024, b"ct"),
+            message_context("bob", "alice", KemVersion::Kyber1024, b"ct"),
+            message_context("alice", "bob", KemVersion::X25519Kyber1024, b"ct"),
+            message_context("alice", "bob", KemVersion::Kyber1024, b"cu"),
+        ] {
+            assert_ne!(base, other);
+            assert_ne!(message_key(b"secret", &base), message_key(b"secret", &other));
+        }
+        // Fields are length-prefixed, so moving bytes across a boundary changes it
+        assert_ne!(
+            message_context("ab", "c", KemVersion::Kyber1024, b"ct"),
+            message_context("a", "bc", KemVersion::Kyber1024, b"ct"),
+        );
+    }
+
+    #[test]
+    fn context_layout() {
+        let context = message_context("al", "bob", KemVersion::X25519Kyber1024, b"ct");
+        let mut expected = b"noid-message".to_vec();
+        expected.extend_from_slice(&[MESSAGE_VERSION, 2, 0, 0, 0, 2]);
+        expected.extend_from_slice(b"al");
+        expected.extend_from_slice(&[0, 0, 0, 3]);
+        expected.extend_from_slice(b"bob");
+        expected.extend_from_slice(&[0, 0, 0, 32]);
+        expected.extend_from_slice(&Sha256::digest(b"ct"));
+        assert_eq!(context, expected);
+    }
+}
@@ -0,0 +1,80 @@
+use crate::crypto::{generate_keypair, hybrid, kyber, mlkem, CryptoError};
+
+/// Which KEM an envelope's key was encapsulated with. Envelopes carry it, so
+/// Kyber-only clients and hybrid clients can talk while hybrid rolls out, and
+/// directory keys carry it so senders know which KEM a device's key is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KemVersion {
+    /// Kyber1024 on its own
+    Kyber1024 = 1,
+    /// X25519 + Kyber1024, combined with HKDF (see `hybrid`)
+    X25519Kyber1024 = 2,
+    /// FIPS 203 ML-KEM-768 (see `mlkem`)
+    MlKem768 = 3,
+    /// FIPS 203 ML-KEM-1024
+    MlKem1024 = 4,
+}
+
+impl KemVersion {
+    pub fn from_u8(version: u8) -> Option<Self> {
+        match version {
+            1 => Some(KemVersion::Kyber1024),
+            2 => Some(KemVersion::X25519Kyber1024),
+            3 => Some(KemVersion::MlKem768),
+            4 => Some(KemVersion::MlKem1024),
+            _ => None,
+        }
+    }
+
+    /// Returns (public_key, secret_key)
+    pub fn generate_keypair(self) -> (Vec<u8>, Vec<u8>) {
+        match self {
+            KemVersion::Kyber1024 => generate_keypair::generate_keypair(),
+            KemVersion::X25519Kyber1024 => hybrid::generate_keypair(),
+            KemVersion::MlKem768 => mlkem::generate_keypair(mlkem::ML_KEM_768),
+            KemVersion::MlKem1024 => mlkem::generate_keypair(mlkem::ML_KEM_1024),
+        }
+    }
+
+    /// Whether `pk` is a well-formed public key for this KEM
+    pub fn validate_public_key(self, pk: &[u8]) -> bool {
+        match self {
+            KemVersion::Kyber1024 => kyber::validate_public_key(pk),
+            KemVersion::X25519Kyber1024 => {
+                pk.len() == hybrid::public_key_bytes() && kyber::validate_public_key(&pk[hybrid::X25519_KEY_LEN..])
+            }
+            KemVersion::MlKem768 => mlkem::validate_public_key(mlkem::ML_KEM_768, pk),
+            KemVersion::MlKem1024 => mlkem::validate_public_key(mlkem::ML_KEM_1024, pk),
+        }
+    }
+
+    /// Length of the KEM ciphertext (for the hybrid, the ephemeral X25519 key
+    /// followed by the Kyber ciphertext)
+    pub fn ciphertext_bytes(self) -> usize {
+        match self {
+            KemVersion::Kyber1024 => pqcrypto_kyber::kyber1024::ciphertext_bytes(),
+            KemVersion::X25519Kyber1024 => hybrid::ciphertext_bytes(),
+            KemVersion::MlKem768 => mlkem::ML_KEM_768.ciphertext_bytes(),
+            KemVersion::MlKem1024 => mlkem::ML_KEM_1024.ciphertext_bytes(),
+        }
+    }
+
+    /// Returns (kem_ciphertext, shared_secret)
+    pub fn encapsulate(self, pk: &[u8]) -> Result<(Vec<u8>, Vec<u8>), CryptoError> {
+        match self {
+            KemVersion::Kyber1024 => kyber::encapsulate(pk),
+            KemVersion::X25519Kyber1024 => hybrid::encapsulate(pk),
+            KemVersion::MlKem768 => mlkem::encapsulate(mlkem::ML_KEM_768, pk),
+            KemVersion::MlKem1024 => mlkem::encapsulate(mlkem::ML_KEM_1024, pk),
+        }
+    }
+
+    pub fn decapsulate(self, kem_ct: &[u8], sk: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        match self {
+            KemVersion::Kyber1024 => kyber::decapsulate(kem_ct, sk),
+            KemVersion::X25519Kyber1024 => hybrid::decapsulate(kem_ct, sk),
+            KemVersion::MlKem768 => mlkem::decapsulate(mlkem::ML_KEM_768, kem_ct, sk),
+            KemVersion::MlKem1024 => mlkem::decapsulate(mlkem::ML_KEM_1024, kem_ct, sk),
+        }
+    }
+}
@@ -1,22 +1,253 @@
 use pqcrypto_kyber::kyber1024;
 use pqcrypto_traits::kem::{PublicKey as _, SecretKey as _, Ciphertext as _, SharedSecret as _};
+use sha3::{digest::{ExtendableOutput, Update}, Digest, Sha3_256, Shake256};
+
+use crate::crypto::CryptoError;
+
+// pqcrypto-kyber 0.8 is PQClean's final Kyber, which already follows the ML-KEM
+// draft: its shared secret is the key K straight out of G(m || H(pk)). The
+// browser (static/kyber.js) is round-3 Kyber, which hands out
+// KDF(K || H(ct)) = SHAKE-256(K || SHA3-256(ct)) instead. Keys, ciphertexts and
+// the IND-CPA scheme underneath are identical, so applying the round-3 KDF here
+// makes both sides agree on the secret for any ciphertext either one produced.
+// (Round 3 also hashes m before use; that only guards against a bad RNG and
+// doesn't change what the other side decapsulates.)
 
 /// Encapsulate a shared secret using receiver's public key
 /// Returns (kyber_ciphertext, shared_secret)
-pub fn encapsulate(pk_bytes: &[u8]) -> (Vec<u8>, Vec<u8>) {
-    let pk_obj = kyber1024::PublicKey::from_bytes(pk_bytes).expect("Bad public key length");
-    let (ct, ss) = kyber1024::encapsulate(&pk_obj);
+pub fn encapsulate(pk_bytes: &[u8]) -> Result<(Vec<u8>, Vec<u8>), CryptoError> {
+    let pk_obj = kyber1024::PublicKey::from_bytes(pk_bytes).map_err(|_| CryptoError::BadKeyLength)?;
+    // Unlike the KEM convention, pqcrypto returns (shared_secret, ciphertext)
+    let (key, ct) = kyber1024::encapsulate(&pk_obj);
     let ct_bytes = ct.as_bytes().to_vec();
-    let ss_bytes = ss.as_bytes().to_vec();
-    // IMPORTANT: The library returns (shared_secret, ciphertext) not (ciphertext, shared_secret)
-    // So we need to swap: ct is actually shared_secret, and ss is actually ciphertext
-    (ss_bytes, ct_bytes)
+    let ss_bytes = round3_kdf(key.as_bytes(), &ct_bytes);
+    Ok((ct_bytes, ss_bytes))
 }
 
 /// Decapsulate shared secret using receiver's secret key
-pub fn decapsulate(ct_bytes: &[u8], sk_bytes: &[u8]) -> Vec<u8> {
-    let ct_obj = kyber1024::Ciphertext::from_bytes(ct_bytes).expect("Bad ciphertext length");
-    let sk_obj = kyber1024::SecretKey::from_bytes(sk_bytes).expect("Bad secret key length");
-    let ss = kyber1024::decapsulate(&ct_obj, &sk_obj);
-    ss.as_bytes().to_vec() // shared secret
+pub fn decapsulate(ct_bytes: &[u8], sk_bytes: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    let ct_obj = kyber1024::Ciphertext::from_bytes(ct_bytes).map_err(|_| CryptoError::BadCiphertext)?;
+    let sk_obj = kyber1024::SecretKey::from_bytes(sk_bytes).map_err(|_| CryptoError::BadKeyLength)?;
+    let key = kyber1024::decapsulate(&ct_obj, &sk_obj);
+    Ok(round3_kdf(key.as_bytes(), ct_bytes))
+}
+
+/// SHAKE-256(key || SHA3-256(ct)), truncated to 32 bytes
+fn round3_kdf(key: &[u8], ct_bytes: &[u8]) -> Vec<u8> {
+    let mut shake = Shake256::default();
+    shake.update(key);
+    shake.update(&Sha3_256::digest(ct_bytes));
+    let mut shared_secret = vec![0u8; 32];
+    shake.finalize_xof_into(&mut shared_secret);
+    shared_secret
+}
+
+/// Kyber modulus q; every encoded public key coefficient must be below it
+const KYBER_Q: u16 = 3329;
+
+/// Check that `pk_bytes` is a well-formed Kyber1024 public key: right length,
+/// and every 12-bit coefficient of the encoded polynomial vector is reduced mod q
+pub fn validate_public_key(pk_bytes: &[u8]) -> bool {
+    if pk_bytes.len() != kyber1024::public_key_bytes() || kyber1024::PublicKey::from_bytes(pk_bytes).is_err() {
+        return false;
+    }
+    // Layout: 4 polys x 384 bytes of packed 12-bit coefficients, then 32-byte seed rho
+    let polyvec = &pk_bytes[..pk_bytes.len() - 32];
+    polyvec.chunks_exact(3).all(|b| {
+        let c0 = (b[0] as u16) | ((b[1] as u16 & 0x0f) << 8);
+        let c1 = ((b[1] as u16) >> 4) | ((b[2] as u16) << 4);
+        c0 < KYBER_Q && c1 < KYBER_Q
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    use aes::cipher::BlockEncrypt;
+    use aes_gcm::{aead::{Aead, Payload}, Aes256Gcm, KeyInit, Nonce};
+    use base64::Engine;
+    use serde_json::Value;
+    use sha2::Sha256;
+
+    use super::*;
+    use crate::crypto::{generate_keypair, kdf, KemVersion};
+
+    // The deterministic entry points behind pqcrypto's keypair/encapsulate, for
+    // replaying the NIST KAT (pqcrypto itself only exposes the randomised API)
+    extern "C" {
+        fn PQCLEAN_KYBER1024_CLEAN_crypto_kem_keypair_derand(pk: *mut u8, sk: *mut u8, coins: *const u8) -> i32;
+        fn PQCLEAN_KYBER1024_CLEAN_crypto_kem_enc_derand(ct: *mut u8, ss: *mut u8, pk: *const u8, coins: *const u8) -> i32;
+    }
+
+    /// `nistkat-sha256` from PQClean's crypto_kem/kyber1024/META.yml: the SHA-256
+    /// of the first entry of the NIST KAT response file
+    const PQCLEAN_KAT_SHA256: &str = "03d6494b74c45d010e61b0328c1ab318c4df3b7f9dbd04d0e35b3468848584b7";
+
+    /// Written by testdata/kyber_js.mjs
+    const KYBER_JS_FIXTURE: &str = include_str!("testdata/kyber_js.json");
+
+    /// NIST's AES-256 CTR_DRBG (rng.c from the PQC KAT generator)
+    struct KatRng {
+        key: [u8; 32],
+        v: [u8; 16],
+    }
+
+    impl KatRng {
+        fn new(entropy: &[u8; 48]) -> Self {
+            let mut rng = KatRng { key: [0; 32], v: [0; 16] };
+            rng.update(Some(entropy));
+            rng
+        }
+
+        fn next_block(&mut self) -> [u8; 16] {
+            for byte in self.v.iter_mut().rev() {
+                *byte = byte.wrapping_add(1);
+                if *byte != 0 {
+                    break;
+                }
+            }
+            let mut block = aes::Block::from(self.v);
+            aes::Aes256::new(&self.key.into()).encrypt_block(&mut block);
+            block.into()
+        }
+
+        fn update(&mut self, provided: Option<&[u8; 48]>) {
+            let mut temp = [0u8; 48];
+            for chunk in temp.chunks_mut(16) {
+                chunk.copy_from_slice(&self.next_block());
+            }
+            if let Some(provided) = provided {
+                temp.iter_mut().zip(provided).for_each(|(t, p)| *t ^= p);
+            }
+            self.key.copy_from_slice(&temp[..32]);
+            self.v.copy_from_slice(&temp[32..]);
+        }
+
+        fn fill(&mut self, out: &mut [u8]) {
+            for chunk in out.chunks_mut(16) {
+                let block = self.next_block();
+                chunk.copy_from_slice(&block[..chunk.len()]);
+            }
+            self.update(None);
+        }
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02X}", b)).collect()
+    }
+
+    fn b64(value: &Value) -> Vec<u8> {
+        base64::engine::general_purpose::STANDARD.decode(value.as_str().expect("base64 string")).expect("valid base64")
+    }
+
+    fn aes_gcm_open(key: &[u8], nonce: &[u8], ciphertext: &[u8], aad: &[u8]) -> Vec<u8> {
+        let payload = Payload { msg: ciphertext, aad };
+        Aes256Gcm::new_from_slice(key).unwrap().decrypt(Nonce::from_slice(nonce), payload).expect("decrypts")
+    }
+
+    #[test]
+    fn pqclean_nist_kat() {
+        let entropy: [u8; 48] = std::array::from_fn(|i| i as u8);
+        let mut seed = [0u8; 48];
+        KatRng::new(&entropy).fill(&mut seed);
+
+        let mut rng = KatRng::new(&seed);
+        let mut coins = [0u8; 64];
+        rng.fill(&mut coins);
+        let (mut pk, mut sk) = (vec![0u8; kyber1024::public_key_bytes()], vec![0u8; kyber1024::secret_key_bytes()]);
+        unsafe { PQCLEAN_KYBER1024_CLEAN_crypto_kem_keypair_derand(pk.as_mut_ptr(), sk.as_mut_ptr(), coins.as_ptr()) };
+        let mut coins = [0u8; 32];
+        rng.fill(&mut coins);
+        let (mut ct, mut key) = (vec![0u8; kyber1024::ciphertext_bytes()], vec![0u8; 32]);
+        unsafe { PQCLEAN_KYBER1024_CLEAN_crypto_kem_enc_derand(ct.as_mut_ptr(), key.as_mut_ptr(), pk.as_ptr(), coins.as_ptr()) };
+
+        let rsp = format!(
+            "count = 0\nseed = {}\npk = {}\nsk = {}\nct = {}\nss = {}\n",
+            hex(&seed),
+            hex(&pk),
+            hex(&sk),
+            hex(&ct),
+            hex(&key)
+        );
+        assert_eq!(hex(&Sha256::digest(rsp.as_bytes())).to_lowercase(), PQCLEAN_KAT_SHA256);
+
+        // The implementation pqcrypto picks at runtime (AVX2 on most x86) agrees
+        // with the KAT, and ours is the round-3 KDF of it
+        let sk_obj = kyber1024::SecretKey::from_bytes(&sk).unwrap();
+        let ct_obj = kyber1024::Ciphertext::from_bytes(&ct).unwrap();
+        assert_eq!(kyber1024::decapsulate(&ct_obj, &sk_obj).as_bytes(), key.as_slice());
+        assert_eq!(decapsulate(&ct, &sk).unwrap(), round3_kdf(&key, &ct));
+    }
+
+    #[test]
+    fn encapsulate_returns_ciphertext_then_secret() {
+        let (pk, sk) = generate_keypair::generate_keypair();
+        let (ct, ss) = encapsulate(&pk).unwrap();
+        assert_eq!(ct.len(), kyber1024::ciphertext_bytes());
+        assert_eq!(ss.len(), 32);
+        assert_eq!(decapsulate(&ct, &sk).unwrap(), ss);
+        assert_eq!(encapsulate(&pk[1..]), Err(CryptoError::BadKeyLength));
+        assert_eq!(decapsulate(&ct[1..], &sk), Err(CryptoError::BadCiphertext));
+    }
+
+    #[test]
+    fn decapsulates_kyber_js() {
+        let fixture: Value = serde_json::from_str(KYBER_JS_FIXTURE).unwrap();
+        assert_eq!(decapsulate(&b64(&fixture["ct"]), &b64(&fixture["sk"])).unwrap(), b64(&fixture["ss"]));
+    }
+
+    #[test]
+    fn opens_browser_envelopes() {
+        let fixture: Value = serde_json::from_str(KYBER_JS_FIXTURE).unwrap();
+        let sk = b64(&fixture["sk"]);
+        for envelope in fixture["envelopes"].as_array().unwrap() {
+            let slot = &envelope["slot"];
+            let (kem, kem_ct, recipient_sk) = match slot["kem"].as_u64() {
+                Some(1) => (KemVersion::Kyber1024, b64(&slot["kyber_ct"]), sk.clone()),
+                Some(2) => (
+                    KemVersion::X25519Kyber1024,
+                    [b64(&slot["x25519_epk"]), b64(&slot["kyber_ct"])].concat(),
+                    [b64(&fixture["x25519_sk"]), sk.clone()].concat(),
+                ),
+                other => panic!("unexpected kem {:?}", other),
+            };
+            let shared_secret = kem.decapsulate(&kem_ct, &recipient_sk).unwrap();
+            let context = kdf::message_context(
+                envelope["from"].as_str().unwrap(),
+                envelope["to"].as_str().unwrap(),
+                kem,
+                &kem_ct,
+            );
+            let wrap_key = kdf::message_key(&shared_secret, &context);
+            let nonce = b64(&envelope["nonce"]);
+            let content_key = aes_gcm_open(&wrap_key, &nonce, &b64(&slot["wrapped_key"]), &context);
+            let plaintext = aes_gcm_open(&content_key, &nonce, &b64(&envelope["ciphertext"]), &[]);
+            assert_eq!(plaintext, envelope["plaintext"].as_str().unwrap().as_bytes());
+        }
+    }
+
+    /// Live round trip through kyber.js in both directions. Needs Node, so it
+    /// only runs with `cargo test -- --ignored`.
+    #[test]
+    #[ignore = "needs node; run with cargo test -- --ignored"]
+    fn round_trips_with_kyber_js() {
+        let script = concat!(env!("CARGO_MANIFEST_DIR"), "/src/crypto/testdata/kyber_js.mjs");
+        let mut node = Command::new("node").arg(script).stdin(Stdio::piped()).stdout(Stdio::piped()).spawn().expect("node must be installed to run this test");
+        let (pk, sk) = generate_keypair::generate_keypair();
+        let (ct, ss) = encapsulate(&pk).unwrap();
+        let engine = base64::engine::general_purpose::STANDARD;
+        let input = serde_json::json!({ "pk": engine.encode(&pk), "sk": engine.encode(&sk), "ct": engine.encode(&ct) });
+        node.stdin.take().unwrap().write_all(input.to_string().as_bytes()).unwrap();
+        let output = node.wait_with_output().unwrap();
+        assert!(output.status.success(), "kyber_js.mjs failed");
+        let output: Value = serde_json::from_slice(&output.stdout).unwrap();
+
+        // Rust encapsulated, kyber.js decapsulated
+        assert_eq!(b64(&output["ss"]), ss);
+        // kyber.js encapsulated, Rust decapsulated
+        assert_eq!(decapsulate(&b64(&output["js_ct"]), &sk).unwrap(), b64(&output["js_ss"]));
+    }
 }
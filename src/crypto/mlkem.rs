@@ -0,0 +1,204 @@
+//! ML-KEM (FIPS 203), the standardised form of Kyber, at the ML-KEM-768 and
+//! ML-KEM-1024 parameter sets. It isn't byte-compatible with the round-3
+//! Kyber1024 in `kyber` (and in the browser's kyber.js): key generation hashes
+//! the seed with the module rank, the message isn't pre-hashed and the shared
+//! secret is K itself rather than KDF(K || H(c)). Keys, ciphertexts and
+//! envelopes are tagged with `KemVersion`, so the two can coexist while users
+//! migrate.
+//!
+//! The KEM itself is PQClean's ML-KEM via pqcrypto-mlkem (the same family as
+//! the pqcrypto-kyber behind `kyber`). PQClean leaves the FIPS 203 input checks
+//! on encapsulation and decapsulation keys to the caller, so they're done here.
+
+use pqcrypto_mlkem::{mlkem1024, mlkem768};
+use pqcrypto_traits::kem::{Ciphertext as _, PublicKey as _, SecretKey as _, SharedSecret as _};
+use sha3::{Digest, Sha3_256};
+
+use crate::crypto::CryptoError;
+
+/// ML-KEM modulus q; every encoded encapsulation key coefficient must be below it
+const Q: u32 = 3329;
+
+/// An ML-KEM parameter set (FIPS 203, section 8), by module rank k
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Params {
+    k: usize,
+}
+
+pub const ML_KEM_768: Params = Params { k: 3 };
+pub const ML_KEM_1024: Params = Params { k: 4 };
+
+impl Params {
+    pub fn public_key_bytes(self) -> usize {
+        384 * self.k + 32
+    }
+
+    pub fn secret_key_bytes(self) -> usize {
+        768 * self.k + 96
+    }
+
+    pub fn ciphertext_bytes(self) -> usize {
+        if self == ML_KEM_768 { mlkem768::ciphertext_bytes() } else { mlkem1024::ciphertext_bytes() }
+    }
+}
+
+/// Generate a keypair, returned as (encapsulation key, decapsulation key)
+pub fn generate_keypair(params: Params) -> (Vec<u8>, Vec<u8>) {
+    if params == ML_KEM_768 {
+        let (pk, sk) = mlkem768::keypair();
+        (pk.as_bytes().to_vec(), sk.as_bytes().to_vec())
+    } else {
+        let (pk, sk) = mlkem1024::keypair();
+        (pk.as_bytes().to_vec(), sk.as_bytes().to_vec())
+    }
+}
+
+/// Encapsulate to an encapsulation key.
+/// Returns (ciphertext, shared_secret)
+pub fn encapsulate(params: Params, ek: &[u8]) -> Result<(Vec<u8>, Vec<u8>), CryptoError> {
+    if !validate_public_key(params, ek) {
+        return Err(CryptoError::BadKeyLength);
+    }
+    // Like pqcrypto-kyber, these return (shared_secret, ciphertext)
+    let (ss, ct) = if params == ML_KEM_768 {
+        let pk = mlkem768::PublicKey::from_bytes(ek).map_err(|_| CryptoError::BadKeyLength)?;
+        let (ss, ct) = mlkem768::encapsulate(&pk);
+        (ss.as_bytes().to_vec(), ct.as_bytes().to_vec())
+    } else {
+        let pk = mlkem1024::PublicKey::from_bytes(ek).map_err(|_| CryptoError::BadKeyLength)?;
+        let (ss, ct) = mlkem1024::encapsulate(&pk);
+        (ss.as_bytes().to_vec(), ct.as_bytes().to_vec())
+    };
+    Ok((ct, ss))
+}
+
+/// Decapsulate with a decapsulation key. A ciphertext that doesn't re-encrypt
+/// to itself yields an unrelated pseudorandom secret (implicit rejection), so
+/// tampering shows up as an AEAD failure further along.
+pub fn decapsulate(params: Params, ct: &[u8], dk: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    if ct.len() != params.ciphertext_bytes() {
+        return Err(CryptoError::BadCiphertext);
+    }
+    // The FIPS 203 decapsulation key check: dk embeds ek followed by H(ek)
+    let k = params.k;
+    if dk.len() != params.secret_key_bytes() || Sha3_256::digest(&dk[384 * k..768 * k + 32])[..] != dk[768 * k + 32..768 * k + 64] {
+        return Err(CryptoError::BadKeyLength);
+    }
+    let ss = if params == ML_KEM_768 {
+        let ct = mlkem768::Ciphertext::from_bytes(ct).map_err(|_| CryptoError::BadCiphertext)?;
+        let sk = mlkem768::SecretKey::from_bytes(dk).map_err(|_| CryptoError::BadKeyLength)?;
+        mlkem768::decapsulate(&ct, &sk).as_bytes().to_vec()
+    } else {
+        let ct = mlkem1024::Ciphertext::from_bytes(ct).map_err(|_| CryptoError::BadCiphertext)?;
+        let sk = mlkem1024::SecretKey::from_bytes(dk).map_err(|_| CryptoError::BadKeyLength)?;
+        mlkem1024::decapsulate(&ct, &sk).as_bytes().to_vec()
+    };
+    Ok(ss)
+}
+
+/// The FIPS 203 encapsulation key check: right length, and every encoded
+/// coefficient already reduced mod q
+pub fn validate_public_key(params: Params, ek: &[u8]) -> bool {
+    if ek.len() != params.public_key_bytes() {
+        return false;
+    }
+    ek[..384 * params.k].chunks_exact(3).all(|b| {
+        let c0 = b[0] as u32 | ((b[1] as u32 & 0x0f) << 8);
+        let c1 = (b[1] as u32 >> 4) | ((b[2] as u32) << 4);
+        c0 < Q && c1 < Q
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+
+    /// NIST's ACVP ML-KEM encapDecap vectors (see "source" in the file)
+    const ACVP_VECTORS: &str = include_str!("testdata/ml_kem_acvp.json");
+
+    fn unhex(value: &Value) -> Vec<u8> {
+        let s = value.as_str().expect("hex string");
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).expect("valid hex")).collect()
+    }
+
+    fn params(group: &Value) -> Params {
+        match group["parameterSet"].as_str() {
+            Some("ML-KEM-768") => ML_KEM_768,
+            Some("ML-KEM-1024") => ML_KEM_1024,
+            other => panic!("unexpected parameter set {:?}", other),
+        }
+    }
+
+    #[test]
+    fn matches_the_fips_203_sizes() {
+        assert_eq!(
+            (ML_KEM_768.public_key_bytes(), ML_KEM_768.secret_key_bytes(), ML_KEM_768.ciphertext_bytes()),
+            (mlkem768::public_key_bytes(), mlkem768::secret_key_bytes(), 1088)
+        );
+        assert_eq!(
+            (ML_KEM_1024.public_key_bytes(), ML_KEM_1024.secret_key_bytes(), ML_KEM_1024.ciphertext_bytes()),
+            (mlkem1024::public_key_bytes(), mlkem1024::secret_key_bytes(), 1568)
+        );
+    }
+
+    #[test]
+    fn decapsulates_acvp_vectors() {
+        let vectors: Value = serde_json::from_str(ACVP_VECTORS).unwrap();
+        let mut rejected = 0;
+        for group in vectors["decapsulation"].as_array().unwrap() {
+            let (params, dk) = (params(group), unhex(&group["dk"]));
+            for test in group["tests"].as_array().unwrap() {
+                let k = decapsulate(params, &unhex(&test["c"]), &dk).unwrap();
+                assert_eq!(k, unhex(&test["k"]), "tcId {}", test["tcId"]);
+                rejected += (test["reason"] == "modify ciphertext") as usize;
+            }
+        }
+        // Implicit rejection is covered, not just valid ciphertexts
+        assert!(rejected > 0);
+
+        for group in vectors["encapsulation"].as_array().unwrap() {
+            let params = params(group);
+            for test in group["tests"].as_array().unwrap() {
+                let (ek, dk) = (unhex(&test["ek"]), unhex(&test["dk"]));
+                assert_eq!(decapsulate(params, &unhex(&test["c"]), &dk).unwrap(), unhex(&test["k"]), "tcId {}", test["tcId"]);
+                // NIST's keys pass our checks, and our encapsulation opens with them
+                assert!(validate_public_key(params, &ek));
+                let (ct, ss) = encapsulate(params, &ek).unwrap();
+                assert_eq!(decapsulate(params, &ct, &dk).unwrap(), ss);
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip_and_input_checks() {
+        for params in [ML_KEM_768, ML_KEM_1024] {
+            let (ek, dk) = generate_keypair(params);
+            assert!(validate_public_key(params, &ek));
+            let (ct, ss) = encapsulate(params, &ek).unwrap();
+            assert_eq!(decapsulate(params, &ct, &dk).unwrap(), ss);
+            // The decapsulation key embeds the encapsulation key
+            assert_eq!(&dk[384 * params.k..768 * params.k + 32], &ek[..]);
+
+            // Tampering gives an unrelated secret, the same one every time
+            let mut tampered = ct.clone();
+            tampered[0] ^= 1;
+            let rejected = decapsulate(params, &tampered, &dk).unwrap();
+            assert_ne!(rejected, ss);
+            assert_eq!(decapsulate(params, &tampered, &dk).unwrap(), rejected);
+
+            // A coefficient of q is out of range
+            let mut bad_ek = ek.clone();
+            bad_ek[0] = (Q & 0xff) as u8;
+            bad_ek[1] = (bad_ek[1] & 0xf0) | (Q >> 8) as u8;
+            assert_eq!(encapsulate(params, &bad_ek), Err(CryptoError::BadKeyLength));
+            assert_eq!(encapsulate(params, &ek[1..]), Err(CryptoError::BadKeyLength));
+
+            let mut bad_dk = dk.clone();
+            bad_dk[400 * params.k] ^= 1;
+            assert_eq!(decapsulate(params, &ct, &bad_dk), Err(CryptoError::BadKeyLength));
+            assert_eq!(decapsulate(params, &ct[1..], &dk), Err(CryptoError::BadCiphertext));
+        }
+    }
+}
@@ -0,0 +1,559 @@
+//! Pairwise sessions with forward secrecy. `sender::send_message` encapsulates
+//! to the recipient's long-term Kyber key every time, so whoever gets that
+//! secret key later can read everything ever sent to it. A session instead
+//! starts with one Kyber encapsulation to the long-term key and then ratchets:
+//!
+//! - Every message is sealed under its own key, taken from a one-way HMAC chain
+//!   (`kdf::chain_step`); used keys and old chain keys are discarded.
+//! - Each chain ("epoch") is seeded by a fresh KEM step: the sender encapsulates
+//!   to the peer's newest ratchet key, which every message advertises. A peer
+//!   rotates its ratchet key once it has been used, and a sender steps again when
+//!   it sees a new peer key or after `STEP_INTERVAL` messages.
+//! - Epoch chains are derived from the session root plus that epoch's KEM
+//!   secret, independently of each other, so epochs may arrive in any order.
+//!   Keys of skipped messages are cached (bounded) for out-of-order delivery.
+//!
+//! Late messages are only readable within those bounds: a receiving chain is
+//! kept for the newest `MAX_RECV_EPOCHS` epochs and our last `MAX_OWN_KEYS`
+//! ratchet keys are kept to open epochs we haven't seen yet. A message whose
+//! epoch was retired (and whose key wasn't cached as skipped), or that was
+//! encapsulated to a ratchet key we've since dropped, fails with `NoKey`.
+//!
+//! The first epochs the initiator sends are encapsulated to the long-term key,
+//! so, as with any non-interactive start, they're only as safe as that key until
+//! the responder answers.
+
+use std::collections::HashMap;
+
+use aes_gcm::{aead::{Aead, KeyInit, OsRng, Payload}, Aes256Gcm, Key, Nonce};
+use rand::RngCore;
+
+use crate::crypto::{generate_keypair::generate_keypair, kdf, kyber, CryptoError};
+
+/// Format version of the header (bound into every message's AAD)
+const HEADER_VERSION: u8 = 1;
+/// Key id standing for the responder's long-term key
+const IDENTITY_KEY_ID: u32 = 0;
+/// Messages sent on one chain before a fresh KEM step, even if the peer hasn't
+/// rotated its key
+const STEP_INTERVAL: u32 = 50;
+/// How far ahead of a chain we'll ratchet for one message
+const MAX_SKIP: u32 = 1000;
+/// Most cached keys of skipped messages
+const MAX_SKIPPED_KEYS: usize = 2000;
+/// Receiving chains kept at once
+const MAX_RECV_EPOCHS: usize = 4;
+/// Own ratchet keys kept for epochs still in flight
+const MAX_OWN_KEYS: usize = 4;
+
+/// One of our ratchet keypairs
+struct KemKey {
+    key_id: u32,
+    pk: Vec<u8>,
+    sk: Vec<u8>,
+}
+
+impl KemKey {
+    fn generate() -> Self {
+        let (pk, sk) = generate_keypair();
+        KemKey { key_id: OsRng.next_u32().max(IDENTITY_KEY_ID + 1), pk, sk }
+    }
+}
+
+/// The peer's newest ratchet key we know of
+struct PeerKey {
+    key_id: u32,
+    pk: Vec<u8>,
+}
+
+struct SendChain {
+    epoch: u32,
+    target_key_id: u32,
+    kem_ct: Vec<u8>,
+    chain_key: [u8; 32],
+    n: u32,
+}
+
+#[derive(Clone)]
+struct RecvChain {
+    chain_key: [u8; 32],
+    /// Index of the message `chain_key` produces the key for
+    n: u32,
+}
+
+/// Receive-side state to apply once a message has authenticated
+enum Pending {
+    /// The key came from the skipped-key cache
+    Skipped,
+    /// A chain advanced (or was started, for a new epoch)
+    Advance { chain: RecvChain, skipped: Vec<(u32, [u8; 32])>, new_epoch: bool },
+}
+
+#[derive(Debug, Clone)]
+pub struct RatchetHeader {
+    /// The session-establishing KEM ciphertext (to the responder's long-term
+    /// key); the initiator repeats it until it hears back
+    pub init_ct: Option<Vec<u8>>,
+    /// Sender's current ratchet key, for the peer's next KEM step
+    pub sender_key_id: u32,
+    pub sender_pk: Vec<u8>,
+    pub epoch: u32,
+    /// Which of the recipient's keys `kem_ct` was encapsulated to
+    pub target_key_id: u32,
+    pub kem_ct: Vec<u8>,
+    /// Index of this message within its epoch
+    pub n: u32,
+}
+
+impl RatchetHeader {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = vec![HEADER_VERSION];
+        match &self.init_ct {
+            Some(ct) => {
+                out.push(1);
+                push_bytes(&mut out, ct);
+            }
+            None => out.push(0),
+        }
+        out.extend_from_slice(&self.sender_key_id.to_be_bytes());
+        push_bytes(&mut out, &self.sender_pk);
+        out.extend_from_slice(&self.epoch.to_be_bytes());
+        out.extend_from_slice(&self.target_key_id.to_be_bytes());
+        push_bytes(&mut out, &self.kem_ct);
+        out.extend_from_slice(&self.n.to_be_bytes());
+        out
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RatchetMessage {
+    pub header: RatchetHeader,
+    pub nonce: [u8; 12],
+    pub ciphertext: Vec<u8>,
+}
+
+/// One side of a pairwise session
+pub struct Session {
+    initiator: bool,
+    root_key: [u8; 32],
+    init_ct: Vec<u8>,
+    /// Whether the peer has shown it has the session (always true for the responder)
+    init_acked: bool,
+    /// Oldest first; the last one is advertised
+    own_keys: Vec<KemKey>,
+    peer_key: PeerKey,
+    /// (epoch, n) of the newest message read; the peer's key comes from it
+    peer_latest: Option<(u32, u32)>,
+    send: Option<SendChain>,
+    next_epoch: u32,
+    recv: HashMap<u32, RecvChain>,
+    /// Epochs below this that aren't in `recv` were retired
+    epoch_floor: u32,
+    skipped: HashMap<(u32, u32), [u8; 32]>,
+}
+
+impl Session {
+    /// Start a session with a peer, given its long-term Kyber public key
+    pub fn initiate(peer_identity_pk: &[u8]) -> Result<Self, CryptoError> {
+        let (init_ct, shared_secret) = kyber::encapsulate(peer_identity_pk)?;
+        Ok(Session {
+            initiator: true,
+            root_key: root_key(&shared_secret),
+            init_ct,
+            init_acked: false,
+            own_keys: vec![KemKey::generate()],
+            peer_key: PeerKey { key_id: IDENTITY_KEY_ID, pk: peer_identity_pk.to_vec() },
+            peer_latest: None,
+            send: None,
+            next_epoch: 0,
+            recv: HashMap::new(),
+            epoch_floor: 0,
+            skipped: HashMap::new(),
+        })
+    }
+
+    /// Set up the responder's side from the first message of a session (one that
+    /// carries `init_ct`), returning the session and that message's plaintext.
+    /// Fails if it isn't a valid session start for our long-term key.
+    pub fn accept(identity_sk: &[u8], msg: &RatchetMessage) -> Result<(Self, Vec<u8>), CryptoError> {
+        let init_ct = msg.header.init_ct.as_ref().ok_or(CryptoError::BadCiphertext)?;
+        if !kyber::validate_public_key(&msg.header.sender_pk) {
+            return Err(CryptoError::BadKeyLength);
+        }
+        let shared_secret = kyber::decapsulate(init_ct, identity_sk)?;
+        let identity = KemKey { key_id: IDENTITY_KEY_ID, pk: Vec::new(), sk: identity_sk.to_vec() };
+        let mut session = Session {
+            initiator: false,
+            root_key: root_key(&shared_secret),
+            init_ct: init_ct.clone(),
+            init_acked: true,
+            own_keys: vec![identity],
+            peer_key: PeerKey { key_id: msg.header.sender_key_id, pk: msg.header.sender_pk.clone() },
+            peer_latest: None,
+            send: None,
+            next_epoch: 0,
+            recv: HashMap::new(),
+            epoch_floor: 0,
+            skipped: HashMap::new(),
+        };
+        let plaintext = session.decrypt(msg)?;
+        Ok((session, plaintext))
+    }
+
+    /// The KEM ciphertext that started this session. An incoming message
+    /// carrying a different one belongs to a new session (see `accept`).
+    pub fn init_ct(&self) -> &[u8] {
+        &self.init_ct
+    }
+
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> Result<RatchetMessage, CryptoError> {
+        let needs_step = match &self.send {
+            None => true,
+            Some(chain) => chain.target_key_id != self.peer_key.key_id || chain.n >= STEP_INTERVAL,
+        };
+        if needs_step {
+            self.kem_step()?;
+        }
+        let chain = self.send.as_mut().expect("kem_step sets the send chain");
+        let (next, message_key) = kdf::chain_step(&chain.chain_key);
+        chain.chain_key = next;
+        let n = chain.n;
+        chain.n += 1;
+
+        let own = self.own_keys.last().expect("at least one own key");
+        let header = RatchetHeader {
+            init_ct: (self.initiator && !self.init_acked).then(|| self.init_ct.clone()),
+            sender_key_id: own.key_id,
+            sender_pk: own.pk.clone(),
+            epoch: chain.epoch,
+            target_key_id: chain.target_key_id,
+            kem_ct: chain.kem_ct.clone(),
+            n,
+        };
+        let mut nonce = [0u8; 12];
+        OsRng.fill_bytes(&mut nonce);
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&message_key));
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: plaintext, aad: &header.encode() })
+            .map_err(|_| CryptoError::MessageTooLong)?;
+        Ok(RatchetMessage { header, nonce, ciphertext })
+    }
+
+    /// Decrypt a message from the peer. `AuthFailed` if it doesn't authenticate,
+    /// `NoKey` if it was already read or is too far out of order. Session state
+    /// only changes once a message has authenticated, so forgeries can't disturb it.
+    pub fn decrypt(&mut self, msg: &RatchetMessage) -> Result<Vec<u8>, CryptoError> {
+        let header = &msg.header;
+        if header.init_ct.as_ref().is_some_and(|ct| *ct != self.init_ct) {
+            return Err(CryptoError::NoKey);
+        }
+        let (message_key, pending) = self.message_key(header)?;
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&message_key));
+        let plaintext = cipher
+            .decrypt(Nonce::from_slice(&msg.nonce), Payload { msg: &msg.ciphertext, aad: &header.encode() })
+            .map_err(|_| CryptoError::AuthFailed)?;
+        self.commit(header, pending);
+        Ok(plaintext)
+    }
+
+    fn send_direction(&self) -> u8 {
+        if self.initiator { 1 } else { 2 }
+    }
+
+    fn recv_direction(&self) -> u8 {
+        if self.initiator { 2 } else { 1 }
+    }
+
+    /// Start a new sending epoch with a fresh encapsulation to the peer's newest key
+    fn kem_step(&mut self) -> Result<(), CryptoError> {
+        let (kem_ct, shared_secret) = kyber::encapsulate(&self.peer_key.pk)?;
+        let epoch = self.next_epoch;
+        self.next_epoch += 1;
+        self.send = Some(SendChain {
+            epoch,
+            target_key_id: self.peer_key.key_id,
+            kem_ct,
+            chain_key: epoch_chain_key(&self.root_key, self.send_direction(), epoch, &shared_secret),
+            n: 0,
+        });
+        Ok(())
+    }
+
+    /// Work out a message's key without changing any state
+    fn message_key(&self, header: &RatchetHeader) -> Result<([u8; 32], Pending), CryptoError> {
+        if let Some(key) = self.skipped.get(&(header.epoch, header.n)) {
+            return Ok((*key, Pending::Skipped));
+        }
+        let (mut chain, new_epoch) = match self.recv.get(&header.epoch) {
+            Some(chain) => (chain.clone(), false),
+            None => {
+                if header.epoch < self.epoch_floor {
+                    return Err(CryptoError::NoKey);
+                }
+                let own = self
+                    .own_keys
+                    .iter()
+                    .find(|k| k.key_id == header.target_key_id)
+                    .ok_or(CryptoError::NoKey)?;
+                let shared_secret = kyber::decapsulate(&header.kem_ct, &own.sk)?;
+                let chain_key = epoch_chain_key(&self.root_key, self.recv_direction(), header.epoch, &shared_secret);
+                (RecvChain { chain_key, n: 0 }, true)
+            }
+        };
+        if header.n < chain.n || header.n - chain.n > MAX_SKIP {
+            return Err(CryptoError::NoKey);
+        }
+        let mut skipped = Vec::new();
+        while chain.n < header.n {
+            let (next, key) = kdf::chain_step(&chain.chain_key);
+            skipped.push((chain.n, key));
+            chain.chain_key = next;
+            chain.n += 1;
+        }
+        let (next, message_key) = kdf::chain_step(&chain.chain_key);
+        chain.chain_key = next;
+        chain.n += 1;
+        Ok((message_key, Pending::Advance { chain, skipped, new_epoch }))
+    }
+
+    fn commit(&mut self, header: &RatchetHeader, pending: Pending) {
+        match pending {
+            Pending::Skipped => {
+                self.skipped.remove(&(header.epoch, header.n));
+            }
+            Pending::Advance { chain, skipped, new_epoch } => {
+                for (n, key) in skipped {
+                    self.skipped.insert((header.epoch, n), key);
+                }
+                while self.skipped.len() > MAX_SKIPPED_KEYS {
+                    let oldest = *self.skipped.keys().min().expect("cache is not empty");
+                    self.skipped.remove(&oldest);
+                }
+                self.recv.insert(header.epoch, chain);
+                if new_epoch {
+                    self.retire_epochs();
+                    self.own_key_used(header.target_key_id);
+                }
+            }
+        }
+        self.init_acked = true;
+        // The peer's current ratchet key is whatever its newest message advertises
+        let position = (header.epoch, header.n);
+        if self.peer_latest.is_none_or(|latest| position > latest) && kyber::validate_public_key(&header.sender_pk) {
+            self.peer_latest = Some(position);
+            if header.sender_key_id != self.peer_key.key_id {
+                self.peer_key = PeerKey { key_id: header.sender_key_id, pk: header.sender_pk.clone() };
+            }
+        }
+    }
+
+    /// Drop the oldest receiving chains past `MAX_RECV_EPOCHS`
+    fn retire_epochs(&mut self) {
+        while self.recv.len() > MAX_RECV_EPOCHS {
+            let oldest = *self.recv.keys().min().expect("recv is not empty");
+            self.recv.remove(&oldest);
+            self.epoch_floor = self.epoch_floor.max(oldest + 1);
+        }
+    }
+
+    /// The peer stepped to one of our keys: if it was our newest, rotate so the
+    /// next step uses a key nobody has used yet. Older keys stay (up to
+    /// `MAX_OWN_KEYS`) so epochs the peer started earlier, whose messages may
+    /// still be in flight, can be opened when they arrive.
+    fn own_key_used(&mut self, key_id: u32) {
+        if self.own_keys.last().is_some_and(|k| k.key_id == key_id) {
+            self.own_keys.push(KemKey::generate());
+        }
+        while self.own_keys.len() > MAX_OWN_KEYS {
+            self.own_keys.remove(0);
+        }
+    }
+}
+
+fn root_key(shared_secret: &[u8]) -> [u8; 32] {
+    kdf::hmac(shared_secret, b"noid-ratchet-root-v1")
+}
+
+fn epoch_chain_key(root_key: &[u8; 32], direction: u8, epoch: u32, shared_secret: &[u8]) -> [u8; 32] {
+    let mut data = b"noid-ratchet-epoch-v1".to_vec();
+    data.push(direction);
+    data.extend_from_slice(&epoch.to_be_bytes());
+    data.extend_from_slice(shared_secret);
+    kdf::hmac(root_key, &data)
+}
+
+fn push_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    out.extend_from_slice(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Alice starts a session with Bob; Bob accepts it from her first message
+    fn start() -> (Session, Session) {
+        let (bob_pk, bob_sk) = generate_keypair();
+        let mut alice = Session::initiate(&bob_pk).unwrap();
+        let first = alice.encrypt(b"hello").unwrap();
+        let (bob, plaintext) = Session::accept(&bob_sk, &first).unwrap();
+        assert_eq!(plaintext, b"hello");
+        (alice, bob)
+    }
+
+    fn send(from: &mut Session, to: &mut Session, text: &[u8]) -> RatchetHeader {
+        let msg = from.encrypt(text).unwrap();
+        assert_eq!(to.decrypt(&msg).unwrap(), text);
+        msg.header
+    }
+
+    /// Everything a forged message must not touch
+    #[derive(Debug, PartialEq)]
+    struct Snapshot {
+        recv: Vec<(u32, [u8; 32], u32)>,
+        skipped: usize,
+        own_keys: Vec<u32>,
+        peer_key_id: u32,
+        peer_latest: Option<(u32, u32)>,
+        init_acked: bool,
+    }
+
+    fn snapshot(s: &Session) -> Snapshot {
+        let mut recv: Vec<_> = s.recv.iter().map(|(epoch, c)| (*epoch, c.chain_key, c.n)).collect();
+        recv.sort();
+        Snapshot {
+            recv,
+            skipped: s.skipped.len(),
+            own_keys: s.own_keys.iter().map(|k| k.key_id).collect(),
+            peer_key_id: s.peer_key.key_id,
+            peer_latest: s.peer_latest,
+            init_acked: s.init_acked,
+        }
+    }
+
+    #[test]
+    fn ping_pong_steps_to_fresh_keys() {
+        let (mut alice, mut bob) = start();
+        let mut last_epoch = None;
+        let mut alice_keys = Vec::new();
+        for round in 0..3 {
+            let from_bob = send(&mut bob, &mut alice, b"pong");
+            let from_alice = send(&mut alice, &mut bob, b"ping");
+            // Alice's session start is only repeated until Bob answers
+            assert!(from_alice.init_ct.is_none());
+            // Each reply steps to the key the other side advertised last
+            assert_eq!(from_alice.target_key_id, from_bob.sender_key_id);
+            assert_ne!(from_alice.target_key_id, IDENTITY_KEY_ID);
+            assert!(last_epoch.is_none_or(|e| from_alice.epoch > e), "round {}", round);
+            last_epoch = Some(from_alice.epoch);
+            alice_keys.push(from_alice.sender_key_id);
+        }
+        alice_keys.dedup();
+        assert_eq!(alice_keys.len(), 3, "alice rotates after every step bob takes to her key");
+    }
+
+    #[test]
+    fn replays_are_refused() {
+        let (mut alice, mut bob) = start();
+        let msg = alice.encrypt(b"once").unwrap();
+        assert_eq!(bob.decrypt(&msg).unwrap(), b"once");
+        assert!(matches!(bob.decrypt(&msg), Err(CryptoError::NoKey)));
+        let reply = bob.encrypt(b"ok").unwrap();
+        alice.decrypt(&reply).unwrap();
+        assert!(matches!(alice.decrypt(&reply), Err(CryptoError::NoKey)));
+    }
+
+    #[test]
+    fn out_of_order_within_and_across_epochs() {
+        let (mut alice, mut bob) = start();
+        let mut sent: Vec<(RatchetMessage, u8)> = (0..3).map(|i| (alice.encrypt(&[i]).unwrap(), i)).collect();
+        send(&mut bob, &mut alice, b"reply");
+        sent.extend((3..6).map(|i| (alice.encrypt(&[i]).unwrap(), i)));
+        assert_ne!(sent[0].0.header.epoch, sent[5].0.header.epoch);
+
+        // Newest epoch first, each epoch back to front
+        for (msg, i) in sent.iter().rev() {
+            assert_eq!(bob.decrypt(msg).unwrap(), [*i]);
+        }
+    }
+
+    #[test]
+    fn late_messages_survive_later_kem_steps() {
+        let (mut alice, mut bob) = start();
+        send(&mut bob, &mut alice, b"reply");
+        // A whole epoch goes missing for a while
+        let late = alice.encrypt(b"late").unwrap();
+        for _ in 1..STEP_INTERVAL {
+            alice.encrypt(b"lost").unwrap();
+        }
+        let next = send(&mut alice, &mut bob, b"next epoch");
+        assert_eq!(next.epoch, late.header.epoch + 1);
+        assert_eq!(next.target_key_id, late.header.target_key_id);
+        // ...and the sessions step twice more before it turns up
+        for _ in 0..2 {
+            send(&mut bob, &mut alice, b"pong");
+            send(&mut alice, &mut bob, b"ping");
+        }
+        assert_eq!(bob.decrypt(&late).unwrap(), b"late");
+    }
+
+    #[test]
+    fn skipping_is_capped() {
+        let (mut alice, mut bob) = start();
+        let mut msg = alice.encrypt(b"x").unwrap();
+        msg.header.n += MAX_SKIP + 1;
+        assert!(matches!(bob.decrypt(&msg), Err(CryptoError::NoKey)));
+
+        // Read only the last message of every epoch until more keys were skipped
+        // than the cache holds
+        let mut skipped = Vec::new();
+        loop {
+            let msg = alice.encrypt(b"skipped").unwrap();
+            if msg.header.n + 1 < STEP_INTERVAL {
+                skipped.push(msg);
+                continue;
+            }
+            bob.decrypt(&msg).unwrap();
+            if skipped.len() > MAX_SKIPPED_KEYS {
+                break;
+            }
+        }
+        assert_eq!(bob.skipped.len(), MAX_SKIPPED_KEYS);
+        assert!(matches!(bob.decrypt(&skipped[0]), Err(CryptoError::NoKey)));
+        assert_eq!(bob.decrypt(skipped.last().unwrap()).unwrap(), b"skipped");
+    }
+
+    #[test]
+    fn forgeries_leave_the_session_unchanged() {
+        let (mut alice, mut bob) = start();
+        send(&mut bob, &mut alice, b"reply");
+        let cached = alice.encrypt(b"cached").unwrap();
+        send(&mut alice, &mut bob, b"skips one");
+        let genuine = alice.encrypt(b"genuine").unwrap();
+        let before = snapshot(&bob);
+
+        let mut tampered = genuine.clone();
+        tampered.ciphertext[0] ^= 1;
+        let mut far_ahead = genuine.clone();
+        far_ahead.header.n += MAX_SKIP;
+        let mut new_key = genuine.clone();
+        let (pk, _) = generate_keypair();
+        new_key.header.sender_key_id ^= 1;
+        new_key.header.sender_pk = pk;
+        let mut new_epoch = alice.encrypt(b"x").unwrap();
+        new_epoch.header.epoch += 7;
+        let mut cached_forgery = cached.clone();
+        cached_forgery.nonce[0] ^= 1;
+        for forged in [&tampered, &far_ahead, &new_key, &new_epoch, &cached_forgery] {
+            assert!(matches!(bob.decrypt(forged), Err(CryptoError::AuthFailed)));
+            assert_eq!(snapshot(&bob), before);
+        }
+        let mut other_session = genuine.clone();
+        other_session.header.init_ct = Some(vec![0; 8]);
+        assert!(matches!(bob.decrypt(&other_session), Err(CryptoError::NoKey)));
+        assert_eq!(snapshot(&bob), before);
+
+        assert_eq!(bob.decrypt(&genuine).unwrap(), b"genuine");
+        assert_eq!(bob.decrypt(&cached).unwrap(), b"cached");
+    }
+}
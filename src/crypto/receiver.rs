@@ -1,18 +1,189 @@
-use aes_gcm::{aead::Aead, Aes256Gcm, Key, Nonce, KeyInit};
+use aes_gcm::{aead::{Aead, Payload}, Aes256Gcm, Key, Nonce, KeyInit};
 
-use crate::crypto::kyber;
+use crate::crypto::{identity, kdf, CryptoError, Envelope};
 
-/// Decrypt a message using Kyber secret key + AES-256-GCM
-pub fn receive_message(ciphertext: &[u8], nonce: &[u8], kyber_ct: &[u8], sk: &[u8]) -> Vec<u8> {
-    // Step 1: Decapsulate shared secret
-    let shared_secret = kyber::decapsulate(kyber_ct, sk);
+/// Decrypt a message from `sender_id` to `recipient_id` using a KEM secret key +
+/// AES-256-GCM, after checking it was signed by the sender's identity key
+/// (`sender_vk`, from the key directory).
+pub fn receive_message(
+    envelope: &Envelope,
+    sk: &[u8],
+    sender_vk: &[u8],
+    sender_id: &str,
+    recipient_id: &str,
+) -> Result<Vec<u8>, CryptoError> {
+    // Step 1: Verify the sender's signature before touching anything else
+    let signed = envelope.signed_bytes(sender_id, recipient_id);
+    if !identity::verify(sender_vk, &signed, &envelope.signature) {
+        return Err(CryptoError::BadSignature);
+    }
+    if envelope.nonce.len() != 12 {
+        return Err(CryptoError::BadCiphertext);
+    }
 
-    // Step 2: Build AES key
-    let key = Key::<Aes256Gcm>::from_slice(&shared_secret[0..32]);
-    let cipher = Aes256Gcm::new(key);
+    // Step 2: Decapsulate shared secret
+    let shared_secret = envelope.kem.decapsulate(&envelope.kem_ct, sk)?;
 
-    // Step 3: Decrypt
-    let nonce_obj = Nonce::from_slice(nonce);
-    cipher.decrypt(nonce_obj, ciphertext).expect("decryption failed")
+    // Step 3: Derive the AES key for this sender, recipient and ciphertext
+    let context = kdf::message_context(sender_id, recipient_id, envelope.kem, &envelope.kem_ct);
+    let key = kdf::message_key(&shared_secret, &context);
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key));
+
+    // Step 4: Decrypt (fails if any part of the context differs)
+    let nonce_obj = Nonce::from_slice(&envelope.nonce);
+    cipher
+        .decrypt(nonce_obj, Payload { msg: &envelope.ciphertext, aad: &context })
+        .map_err(|_| CryptoError::AuthFailed)
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{sender, KemVersion};
+
+    const KEMS: [KemVersion; 4] =
+        [KemVersion::Kyber1024, KemVersion::X25519Kyber1024, KemVersion::MlKem768, KemVersion::MlKem1024];
+
+    /// Open `envelope` with a key derived under `key_context` and `aad` as the
+    /// associated data, skipping the signature check
+    fn open_with(envelope: &Envelope, sk: &[u8], key_context: &[u8], aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let shared_secret = envelope.kem.decapsulate(&envelope.kem_ct, sk)?;
+        let key = kdf::message_key(&shared_secret, key_context);
+        Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))
+            .decrypt(Nonce::from_slice(&envelope.nonce), Payload { msg: &envelope.ciphertext, aad })
+            .map_err(|_| CryptoError::AuthFailed)
+    }
+
+    #[test]
+    fn round_trips_every_kem() {
+        let (alice_vk, alice_seed) = identity::generate_identity();
+        for kem in KEMS {
+            let (pk, sk) = kem.generate_keypair();
+            let envelope = sender::send_message(b"hi bob", &pk, kem, "alice", "bob", &alice_seed).unwrap();
+            assert_eq!(envelope.kem, kem);
+            assert_eq!(receive_message(&envelope, &sk, &alice_vk, "alice", "bob").unwrap(), b"hi bob");
+        }
+    }
+
+    #[test]
+    fn mismatched_context_fails_to_decrypt() {
+        let (_, alice_seed) = identity::generate_identity();
+        let (pk, sk) = KemVersion::Kyber1024.generate_keypair();
+        let envelope = sender::send_message(b"hi bob", &pk, KemVersion::Kyber1024, "alice", "bob", &alice_seed).unwrap();
+        let right = kdf::message_context("alice", "bob", envelope.kem, &envelope.kem_ct);
+        let wrong = kdf::message_context("alice", "carol", envelope.kem, &envelope.kem_ct);
+
+        assert_eq!(open_with(&envelope, &sk, &right, &right).unwrap(), b"hi bob");
+        // Right key, wrong associated data
+        assert_eq!(open_with(&envelope, &sk, &right, &wrong), Err(CryptoError::AuthFailed));
+        // Key derived under the wrong context, right associated data
+        assert_eq!(open_with(&envelope, &sk, &wrong, &right), Err(CryptoError::AuthFailed));
+    }
+
+    #[test]
+    fn rejects_a_message_re_signed_by_someone_else() {
+        let (_, alice_seed) = identity::generate_identity();
+        let (mallory_vk, mallory_seed) = identity::generate_identity();
+        let (pk, sk) = KemVersion::X25519Kyber1024.generate_keypair();
+        let mut envelope =
+            sender::send_message(b"hi bob", &pk, KemVersion::X25519Kyber1024, "alice", "bob", &alice_seed).unwrap();
+
+        // Mallory passes alice's ciphertext off as her own: her signature is
+        // good, but the key and AAD are bound to alice as the sender
+        envelope.signature = identity::sign(&mallory_seed, &envelope.signed_bytes("mallory", "bob")).unwrap();
+        assert_eq!(receive_message(&envelope, &sk, &mallory_vk, "mallory", "bob"), Err(CryptoError::AuthFailed));
+    }
+
+    #[test]
+    fn tampered_header_fails_the_signature() {
+        let (alice_vk, alice_seed) = identity::generate_identity();
+        let (pk, sk) = KemVersion::Kyber1024.generate_keypair();
+        let envelope = sender::send_message(b"hi bob", &pk, KemVersion::Kyber1024, "alice", "bob", &alice_seed).unwrap();
+
+        let mut kem_ct = envelope.clone();
+        kem_ct.kem_ct[0] ^= 1;
+        let mut nonce = envelope.clone();
+        nonce.nonce[0] ^= 1;
+        let mut kem = envelope.clone();
+        kem.kem = KemVersion::MlKem1024;
+        for tampered in [kem_ct, nonce, kem] {
+            assert_eq!(receive_message(&tampered, &sk, &alice_vk, "alice", "bob"), Err(CryptoError::BadSignature));
+        }
+        // The sender and recipient are part of the signed header too
+        assert_eq!(receive_message(&envelope, &sk, &alice_vk, "alice", "carol"), Err(CryptoError::BadSignature));
+        assert_eq!(receive_message(&envelope, &sk, &alice_vk, "carol", "bob"), Err(CryptoError::BadSignature));
+    }
+
+    #[test]
+    fn tampered_ciphertext_fails_the_signature() {
+        let (alice_vk, alice_seed) = identity::generate_identity();
+        let (pk, sk) = KemVersion::X25519Kyber1024.generate_keypair();
+        let envelope =
+            sender::send_message(b"hi bob", &pk, KemVersion::X25519Kyber1024, "alice", "bob", &alice_seed).unwrap();
+
+        let mut flipped = envelope.clone();
+        flipped.ciphertext[0] ^= 1;
+        let mut truncated = envelope.clone();
+        truncated.ciphertext.pop();
+        let mut signature = envelope.clone();
+        signature.signature[0] ^= 1;
+        for tampered in [flipped, truncated, signature] {
+            assert_eq!(receive_message(&tampered, &sk, &alice_vk, "alice", "bob"), Err(CryptoError::BadSignature));
+        }
+    }
+
+    #[test]
+    fn wrong_verifying_key_fails_the_signature() {
+        let (_, alice_seed) = identity::generate_identity();
+        let (carol_vk, _) = identity::generate_identity();
+        let (pk, sk) = KemVersion::MlKem768.generate_keypair();
+        let envelope = sender::send_message(b"hi bob", &pk, KemVersion::MlKem768, "alice", "bob", &alice_seed).unwrap();
+
+        assert_eq!(receive_message(&envelope, &sk, &carol_vk, "alice", "bob"), Err(CryptoError::BadSignature));
+    }
+
+    #[test]
+    fn bad_key_lengths_are_reported() {
+        let (alice_vk, alice_seed) = identity::generate_identity();
+        for kem in KEMS {
+            let (pk, sk) = kem.generate_keypair();
+            assert_eq!(
+                sender::send_message(b"hi", &pk[1..], kem, "alice", "bob", &alice_seed).unwrap_err(),
+                CryptoError::BadKeyLength,
+            );
+            let envelope = sender::send_message(b"hi", &pk, kem, "alice", "bob", &alice_seed).unwrap();
+            assert_eq!(receive_message(&envelope, &sk[1..], &alice_vk, "alice", "bob"), Err(CryptoError::BadKeyLength));
+        }
+        let (pk, _) = KemVersion::Kyber1024.generate_keypair();
+        assert_eq!(
+            sender::send_message(b"hi", &pk, KemVersion::Kyber1024, "alice", "bob", &alice_seed[1..]).unwrap_err(),
+            CryptoError::BadKeyLength,
+        );
+    }
+
+    #[test]
+    fn truncated_envelopes_are_reported() {
+        let (alice_vk, alice_seed) = identity::generate_identity();
+        let (pk, sk) = KemVersion::Kyber1024.generate_keypair();
+        let envelope = sender::send_message(b"hi bob", &pk, KemVersion::Kyber1024, "alice", "bob", &alice_seed).unwrap();
+        // Re-signed by alice, so the checks past the signature are reached
+        let resign = |mut envelope: Envelope| {
+            envelope.signature = identity::sign(&alice_seed, &envelope.signed_bytes("alice", "bob")).unwrap();
+            envelope
+        };
+
+        let mut nonce = envelope.clone();
+        nonce.nonce.truncate(8);
+        assert_eq!(receive_message(&resign(nonce), &sk, &alice_vk, "alice", "bob"), Err(CryptoError::BadCiphertext));
+        let mut kem_ct = envelope.clone();
+        kem_ct.kem_ct.truncate(100);
+        assert_eq!(receive_message(&resign(kem_ct), &sk, &alice_vk, "alice", "bob"), Err(CryptoError::BadCiphertext));
+        let mut ciphertext = envelope.clone();
+        ciphertext.ciphertext.truncate(4);
+        assert_eq!(receive_message(&resign(ciphertext), &sk, &alice_vk, "alice", "bob"), Err(CryptoError::AuthFailed));
+        // Without a valid signature nothing else is looked at
+        let mut signature = envelope;
+        signature.signature.truncate(100);
+        assert_eq!(receive_message(&signature, &sk, &alice_vk, "alice", "bob"), Err(CryptoError::BadSignature));
+    }
+}
@@ -1,25 +1,39 @@
-use aes_gcm::{aead::{Aead, KeyInit, OsRng}, Aes256Gcm, Key, Nonce};
+use aes_gcm::{aead::{Aead, KeyInit, OsRng, Payload}, Aes256Gcm, Key, Nonce};
 use rand::RngCore;
 
-use crate::crypto::kyber;
+use crate::crypto::{identity, kdf, CryptoError, Envelope, KemVersion};
 
-/// Encrypts a message using a Kyber-shared secret (AES-256-GCM)
-pub fn send_message(message: &[u8], receiver_pk: &[u8]) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
-    // Step 1: Encapsulate with Kyber
-    let (kyber_ct, shared_secret) = kyber::encapsulate(receiver_pk);
+/// Encrypts a message from `sender_id` to `recipient_id` using a KEM-shared
+/// secret (AES-256-GCM), signed with the sender's identity key.
+/// `receiver_pk` must be a key for `kem`.
+pub fn send_message(
+    message: &[u8],
+    receiver_pk: &[u8],
+    kem: KemVersion,
+    sender_id: &str,
+    recipient_id: &str,
+    signing_seed: &[u8],
+) -> Result<Envelope, CryptoError> {
+    // Step 1: Encapsulate (Kyber, or hybrid X25519 + Kyber)
+    let (kem_ct, shared_secret) = kem.encapsulate(receiver_pk)?;
 
-    // Step 2: Prepare AES key from shared secret
-    let key = Key::<Aes256Gcm>::from_slice(&shared_secret[0..32]); // 256-bit
-    let cipher = Aes256Gcm::new(key);
+    // Step 2: Derive the AES key, bound to who/what this message is for
+    let context = kdf::message_context(sender_id, recipient_id, kem, &kem_ct);
+    let key = kdf::message_key(&shared_secret, &context);
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key));
 
     // Step 3: Random nonce
     let mut nonce_bytes = [0u8; 12];
     OsRng.fill_bytes(&mut nonce_bytes);
     let nonce = Nonce::from_slice(&nonce_bytes);
 
-    // Step 4: Encrypt
-    let ciphertext = cipher.encrypt(nonce, message).expect("encryption failed");
+    // Step 4: Encrypt, authenticating the same context
+    let ciphertext = cipher
+        .encrypt(nonce, Payload { msg: message, aad: &context })
+        .map_err(|_| CryptoError::MessageTooLong)?;
 
-    // Return: AES ciphertext, nonce, Kyber ciphertext (NOT shared secret!)
-    (ciphertext, nonce_bytes.to_vec(), kyber_ct)
+    // Step 5: Sign header + ciphertext (KEM ciphertext, NOT shared secret!)
+    let mut envelope = Envelope { kem, kem_ct, nonce: nonce_bytes.to_vec(), ciphertext, signature: Vec::new() };
+    envelope.signature = identity::sign(signing_seed, &envelope.signed_bytes(sender_id, recipient_id))?;
+    Ok(envelope)
 }
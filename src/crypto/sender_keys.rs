@@ -0,0 +1,413 @@
+//! Sender keys for group messages. Each member has a symmetric chain key per
+//! group, sent once to every other member inside a pairwise Kyber-encrypted
+//! distribution message. Group messages are then AES-256-GCM under per-message
+//! keys ratcheted forward from that chain, so a message costs one symmetric
+//! encryption no matter how big the group is. Membership changes rotate the
+//! sender key: someone who left never receives the new chain, and someone who
+//! joined can't read anything sent under the old one.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use aes_gcm::{aead::{Aead, KeyInit, OsRng, Payload}, Aes256Gcm, Key, Nonce};
+use rand::RngCore;
+
+use crate::crypto::{kdf, receiver, sender, CryptoError, Envelope, KemVersion};
+
+/// Format version of distribution messages
+const DISTRIBUTION_VERSION: u8 = 1;
+/// How far past the newest message we'll ratchet a peer's chain (bounds the
+/// work and the skipped-key cache a single bogus iteration number can cause)
+const MAX_SKIP: u32 = 1000;
+/// Chains kept per peer, so messages sent just before a rotation still decrypt
+const MAX_CHAINS_PER_SENDER: usize = 2;
+
+/// Our own sending chain
+struct SenderKey {
+    key_id: u32,
+    chain_key: [u8; 32],
+    iteration: u32,
+}
+
+impl SenderKey {
+    fn generate() -> Self {
+        let mut chain_key = [0u8; 32];
+        OsRng.fill_bytes(&mut chain_key);
+        SenderKey { key_id: OsRng.next_u32(), chain_key, iteration: 0 }
+    }
+
+    /// Message key for the next message, advancing the chain
+    fn next_message_key(&mut self) -> (u32, [u8; 32]) {
+        let (next, message_key) = kdf::chain_step(&self.chain_key);
+        let iteration = self.iteration;
+        self.chain_key = next;
+        self.iteration += 1;
+        (iteration, message_key)
+    }
+}
+
+/// A peer's chain as far as we've followed it
+struct ReceiverChain {
+    key_id: u32,
+    chain_key: [u8; 32],
+    /// Iteration `chain_key` produces the key for
+    iteration: u32,
+    /// Keys of messages we skipped over, for out-of-order delivery
+    skipped: HashMap<u32, [u8; 32]>,
+}
+
+/// Receive-side change to apply once a message has authenticated
+enum Pending {
+    /// The key came from the skipped-key cache
+    Skipped,
+    /// The chain moves past the message, caching the keys it stepped over
+    Advance { chain_key: [u8; 32], iteration: u32, skipped: Vec<(u32, [u8; 32])> },
+}
+
+impl ReceiverChain {
+    /// Work out the key for `iteration` without changing the chain
+    fn message_key(&self, iteration: u32) -> Option<([u8; 32], Pending)> {
+        if iteration < self.iteration {
+            return self.skipped.get(&iteration).map(|key| (*key, Pending::Skipped));
+        }
+        if iteration - self.iteration > MAX_SKIP {
+            return None;
+        }
+        let mut chain_key = self.chain_key;
+        let mut skipped = Vec::new();
+        for n in self.iteration..iteration {
+            let (next, skipped_key) = kdf::chain_step(&chain_key);
+            skipped.push((n, skipped_key));
+            chain_key = next;
+        }
+        let (next, message_key) = kdf::chain_step(&chain_key);
+        Some((message_key, Pending::Advance { chain_key: next, iteration: iteration + 1, skipped }))
+    }
+
+    fn commit(&mut self, iteration: u32, pending: Pending) {
+        match pending {
+            Pending::Skipped => {
+                self.skipped.remove(&iteration);
+            }
+            Pending::Advance { chain_key, iteration, skipped } => {
+                self.chain_key = chain_key;
+                self.iteration = iteration;
+                self.skipped.extend(skipped);
+                // Keep the cache bounded: drop the oldest skipped keys first
+                while self.skipped.len() > MAX_SKIP as usize {
+                    let oldest = *self.skipped.keys().min().expect("cache is not empty");
+                    self.skipped.remove(&oldest);
+                }
+            }
+        }
+    }
+}
+
+/// One encrypted group message
+#[derive(Debug, Clone)]
+pub struct GroupMessage {
+    pub key_id: u32,
+    pub iteration: u32,
+    pub nonce: [u8; 12],
+    pub ciphertext: Vec<u8>,
+}
+
+/// Our side of one group: our own sender key plus every other member's
+pub struct GroupSession {
+    group_id: String,
+    me: String,
+    own: SenderKey,
+    members: BTreeSet<String>,
+    /// Newest chain last
+    peers: HashMap<String, Vec<ReceiverChain>>,
+    /// Every (sender, key id) we've installed a chain for, including chains since
+    /// dropped, so a replayed distribution can't reset a chain to an earlier
+    /// iteration and make already-read messages decrypt again
+    accepted: HashSet<(String, u32)>,
+}
+
+impl GroupSession {
+    /// Start a session for `me` in `group_id` with a fresh sender key. Its
+    /// distribution has to be sent to every other member before they can read
+    /// our messages (see `seal_distribution`).
+    pub fn new(group_id: &str, me: &str, members: impl IntoIterator<Item = String>) -> Self {
+        GroupSession {
+            group_id: group_id.to_string(),
+            me: me.to_string(),
+            own: SenderKey::generate(),
+            members: members.into_iter().collect(),
+            peers: HashMap::new(),
+            accepted: HashSet::new(),
+        }
+    }
+
+    pub fn members(&self) -> &BTreeSet<String> {
+        &self.members
+    }
+
+    /// Update the member list. If it changed, our sender key is rotated (returns
+    /// true): the new key must be distributed to the current members, and chains
+    /// of members who left are forgotten so their messages stop decrypting.
+    pub fn set_members(&mut self, members: impl IntoIterator<Item = String>) -> bool {
+        let members: BTreeSet<String> = members.into_iter().collect();
+        if members == self.members {
+            return false;
+        }
+        self.peers.retain(|sender, _| members.contains(sender));
+        self.members = members;
+        self.own = SenderKey::generate();
+        true
+    }
+
+    /// Our current sender key (at its current iteration), encrypted to one member's
+    /// Kyber public key and signed with our identity key
+    pub fn seal_distribution(&self, member: &str, member_pk: &[u8], signing_seed: &[u8]) -> Result<Envelope, CryptoError> {
+        let plain = encode_distribution(&self.group_id, &self.me, &self.own);
+        sender::send_message(&plain, member_pk, KemVersion::Kyber1024, &self.me, member, signing_seed)
+    }
+
+    /// Install `sender`'s sender key from a distribution they sealed to us.
+    /// Fails if `sender` isn't a member (`NoKey`), it isn't signed by
+    /// `sender_vk`, doesn't decrypt, or isn't a distribution for this group. A
+    /// distribution for a key we've already accepted is ignored.
+    pub fn accept_distribution(
+        &mut self,
+        sender: &str,
+        sender_vk: &[u8],
+        sealed: &Envelope,
+        our_sk: &[u8],
+    ) -> Result<(), CryptoError> {
+        if !self.members.contains(sender) {
+            return Err(CryptoError::NoKey);
+        }
+        // Distributions are always sealed with plain Kyber (see `seal_distribution`)
+        if sealed.kem != KemVersion::Kyber1024 {
+            return Err(CryptoError::BadCiphertext);
+        }
+        let plain = receiver::receive_message(sealed, our_sk, sender_vk, sender, &self.me)?;
+        let chain = decode_distribution(&plain, &self.group_id, sender).ok_or(CryptoError::BadCiphertext)?;
+        if !self.accepted.insert((sender.to_string(), chain.key_id)) {
+            return Ok(());
+        }
+        let chains = self.peers.entry(sender.to_string()).or_default();
+        chains.push(chain);
+        if chains.len() > MAX_CHAINS_PER_SENDER {
+            chains.remove(0);
+        }
+        Ok(())
+    }
+
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> Result<GroupMessage, CryptoError> {
+        let (iteration, message_key) = self.own.next_message_key();
+        let key_id = self.own.key_id;
+        let mut nonce = [0u8; 12];
+        OsRng.fill_bytes(&mut nonce);
+        let aad = associated_data(&self.group_id, &self.me, key_id, iteration);
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&message_key));
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: plaintext, aad: &aad })
+            .map_err(|_| CryptoError::MessageTooLong)?;
+        Ok(GroupMessage { key_id, iteration, nonce, ciphertext })
+    }
+
+    /// Decrypt a message from `sender`. `NoKey` if we have no matching chain or
+    /// the message key was already used, `AuthFailed` if authentication fails.
+    /// The chain only moves once a message has authenticated, so forgeries can't
+    /// advance it or evict cached keys.
+    pub fn decrypt(&mut self, sender: &str, msg: &GroupMessage) -> Result<Vec<u8>, CryptoError> {
+        let chain = self
+            .peers
+            .get_mut(sender)
+            .and_then(|chains| chains.iter_mut().find(|c| c.key_id == msg.key_id))
+            .ok_or(CryptoError::NoKey)?;
+        let (message_key, pending) = chain.message_key(msg.iteration).ok_or(CryptoError::NoKey)?;
+        let aad = associated_data(&self.group_id, sender, msg.key_id, msg.iteration);
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&message_key));
+        let plaintext = cipher
+            .decrypt(Nonce::from_slice(&msg.nonce), Payload { msg: &msg.ciphertext, aad: &aad })
+            .map_err(|_| CryptoError::AuthFailed)?;
+        chain.commit(msg.iteration, pending);
+        Ok(plaintext)
+    }
+}
+
+fn associated_data(group_id: &str, sender: &str, key_id: u32, iteration: u32) -> Vec<u8> {
+    let mut aad = b"noid-sender-key-v1".to_vec();
+    push_str(&mut aad, group_id);
+    push_str(&mut aad, sender);
+    aad.extend_from_slice(&key_id.to_be_bytes());
+    aad.extend_from_slice(&iteration.to_be_bytes());
+    aad
+}
+
+fn push_str(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(&(s.len() as u32).to_be_bytes());
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn take_str<'a>(buf: &mut &'a [u8]) -> Option<&'a str> {
+    let len = u32::from_be_bytes(buf.get(..4)?.try_into().ok()?) as usize;
+    let s = std::str::from_utf8(buf.get(4..4 + len)?).ok()?;
+    *buf = &buf[4 + len..];
+    Some(s)
+}
+
+/// version | key_id | iteration | chain_key | group_id | sender (strings length-prefixed)
+fn encode_distribution(group_id: &str, sender: &str, key: &SenderKey) -> Vec<u8> {
+    let mut out = vec![DISTRIBUTION_VERSION];
+    out.extend_from_slice(&key.key_id.to_be_bytes());
+    out.extend_from_slice(&key.iteration.to_be_bytes());
+    out.extend_from_slice(&key.chain_key);
+    push_str(&mut out, group_id);
+    push_str(&mut out, sender);
+    out
+}
+
+fn decode_distribution(buf: &[u8], group_id: &str, sender: &str) -> Option<ReceiverChain> {
+    if buf.len() < 41 || buf[0] != DISTRIBUTION_VERSION {
+        return None;
+    }
+    let key_id = u32::from_be_bytes(buf[1..5].try_into().ok()?);
+    let iteration = u32::from_be_bytes(buf[5..9].try_into().ok()?);
+    let chain_key: [u8; 32] = buf[9..41].try_into().ok()?;
+    let mut rest = &buf[41..];
+    // The distribution names its group and sender, so it can't be replayed into
+    // another group or passed off as someone else's key
+    if take_str(&mut rest)? != group_id || take_str(&mut rest)? != sender || !rest.is_empty() {
+        return None;
+    }
+    Some(ReceiverChain { key_id, chain_key, iteration, skipped: HashMap::new() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{generate_keypair::generate_keypair, identity};
+
+    struct Member {
+        name: &'static str,
+        kem_pk: Vec<u8>,
+        kem_sk: Vec<u8>,
+        vk: Vec<u8>,
+        seed: Vec<u8>,
+        session: GroupSession,
+    }
+
+    fn member(name: &'static str, members: &[&str]) -> Member {
+        let (kem_pk, kem_sk) = generate_keypair();
+        let (vk, seed) = identity::generate_identity();
+        let session = GroupSession::new("g1", name, members.iter().map(|m| m.to_string()));
+        Member { name, kem_pk, kem_sk, vk, seed, session }
+    }
+
+    /// Send `from`'s current sender key to `to`
+    fn distribute(from: &Member, to: &mut Member) {
+        let sealed = from.session.seal_distribution(to.name, &to.kem_pk, &from.seed).unwrap();
+        to.session.accept_distribution(from.name, &from.vk, &sealed, &to.kem_sk).unwrap();
+    }
+
+    fn pair() -> (Member, Member) {
+        let alice = member("alice", &["alice", "bob"]);
+        let mut bob = member("bob", &["alice", "bob"]);
+        distribute(&alice, &mut bob);
+        (alice, bob)
+    }
+
+    #[test]
+    fn messages_decrypt_in_any_order_but_only_once() {
+        let (mut alice, mut bob) = pair();
+        let sent: Vec<GroupMessage> = (0..4).map(|i| alice.session.encrypt(format!("m{}", i).as_bytes()).unwrap()).collect();
+        for i in [2, 0, 3, 1] {
+            assert_eq!(bob.session.decrypt("alice", &sent[i]).unwrap(), format!("m{}", i).as_bytes());
+        }
+        assert!(matches!(bob.session.decrypt("alice", &sent[1]), Err(CryptoError::NoKey)));
+        assert!(matches!(bob.session.decrypt("carol", &sent[0]), Err(CryptoError::NoKey)));
+    }
+
+    #[test]
+    fn forged_messages_leave_the_chain_alone() {
+        let (mut alice, mut bob) = pair();
+        let sent: Vec<GroupMessage> = (0..3).map(|_| alice.session.encrypt(b"hi").unwrap()).collect();
+        bob.session.decrypt("alice", &sent[2]).unwrap();
+
+        // Far ahead: would have moved the chain and evicted the keys for 0 and 1
+        let far = GroupMessage { iteration: sent[2].iteration + MAX_SKIP, ..sent[2].clone() };
+        assert!(matches!(bob.session.decrypt("alice", &far), Err(CryptoError::AuthFailed)));
+        // A cached iteration with a bad tag mustn't use the cached key up
+        let mut tampered = sent[1].clone();
+        tampered.ciphertext[0] ^= 1;
+        assert!(matches!(bob.session.decrypt("alice", &tampered), Err(CryptoError::AuthFailed)));
+
+        assert_eq!(bob.session.decrypt("alice", &sent[0]).unwrap(), b"hi");
+        assert_eq!(bob.session.decrypt("alice", &sent[1]).unwrap(), b"hi");
+        let next = alice.session.encrypt(b"next").unwrap();
+        assert_eq!(bob.session.decrypt("alice", &next).unwrap(), b"next");
+    }
+
+    #[test]
+    fn rotation_keeps_the_previous_chain_for_late_messages() {
+        let (mut alice, mut bob) = pair();
+        let before = alice.session.encrypt(b"before").unwrap();
+        let mut carol = member("carol", &["alice", "bob", "carol"]);
+        let everyone = ["alice", "bob", "carol"].map(String::from);
+        assert!(alice.session.set_members(everyone.clone()));
+        assert!(!alice.session.set_members(everyone.clone()));
+        bob.session.set_members(everyone);
+        distribute(&alice, &mut bob);
+        distribute(&alice, &mut carol);
+
+        let after = alice.session.encrypt(b"after").unwrap();
+        assert_ne!(after.key_id, before.key_id);
+        assert_eq!(bob.session.decrypt("alice", &after).unwrap(), b"after");
+        assert_eq!(bob.session.decrypt("alice", &before).unwrap(), b"before");
+        // A new member can't read what was sent before they joined
+        assert_eq!(carol.session.decrypt("alice", &after).unwrap(), b"after");
+        assert!(matches!(carol.session.decrypt("alice", &before), Err(CryptoError::NoKey)));
+    }
+
+    #[test]
+    fn removed_members_can_no_longer_read_or_be_read() {
+        let trio = ["alice", "bob", "carol"];
+        let mut alice = member("alice", &trio);
+        let mut bob = member("bob", &trio);
+        let mut carol = member("carol", &trio);
+        distribute(&alice, &mut bob);
+        distribute(&alice, &mut carol);
+        distribute(&carol, &mut bob);
+        let from_carol = carol.session.encrypt(b"still here").unwrap();
+
+        let remaining = ["alice", "bob"].map(String::from);
+        alice.session.set_members(remaining.clone());
+        bob.session.set_members(remaining);
+        distribute(&alice, &mut bob);
+
+        let after = alice.session.encrypt(b"carol is gone").unwrap();
+        assert_eq!(bob.session.decrypt("alice", &after).unwrap(), b"carol is gone");
+        assert!(matches!(carol.session.decrypt("alice", &after), Err(CryptoError::NoKey)));
+        assert!(matches!(bob.session.decrypt("carol", &from_carol), Err(CryptoError::NoKey)));
+        // Nor can a removed member push a new key in
+        let sealed = carol.session.seal_distribution("bob", &bob.kem_pk, &carol.seed).unwrap();
+        assert!(matches!(bob.session.accept_distribution("carol", &carol.vk, &sealed, &bob.kem_sk), Err(CryptoError::NoKey)));
+    }
+
+    #[test]
+    fn replayed_distributions_cant_rewind_a_chain() {
+        let (mut alice, mut bob) = pair();
+        let first = alice.session.seal_distribution("bob", &bob.kem_pk, &alice.seed).unwrap();
+        let read = alice.session.encrypt(b"read once").unwrap();
+        assert_eq!(bob.session.decrypt("alice", &read).unwrap(), b"read once");
+
+        // The relay (or anyone who kept it) sends the original distribution again
+        bob.session.accept_distribution("alice", &alice.vk, &first, &bob.kem_sk).unwrap();
+        assert!(matches!(bob.session.decrypt("alice", &read), Err(CryptoError::NoKey)));
+
+        // Still refused once the chain has been rotated out and dropped
+        let both = ["alice", "bob"].map(String::from);
+        for extra in ["carol", "dave"] {
+            alice.session.set_members([both[0].clone(), both[1].clone(), extra.to_string()]);
+            distribute(&alice, &mut bob);
+        }
+        alice.session.set_members(both);
+        bob.session.accept_distribution("alice", &alice.vk, &first, &bob.kem_sk).unwrap();
+        assert!(matches!(bob.session.decrypt("alice", &read), Err(CryptoError::NoKey)));
+    }
+}
@@ -0,0 +1,249 @@
+//! Streaming AES-256-GCM for files too big to hold in memory, using the STREAM
+//! construction (Hoang, Reyhanitabar, Rogaway, Vizár): the input is cut into
+//! fixed-size chunks, each sealed under nonce = prefix || counter || last-flag,
+//! so chunks can't be reordered, dropped or truncated away without decryption
+//! failing.
+//!
+//! Format: version (1 byte) | nonce prefix (7 bytes) | chunks. Every chunk is
+//! `CHUNK_SIZE` bytes of plaintext plus a 16-byte tag, except the last, which
+//! holds whatever is left (possibly nothing) and is sealed with the last-flag
+//! set. Nonce = prefix | 32-bit big-endian chunk counter | 0x00, or 0x01 for
+//! the last chunk; the browser builds the same nonces with WebCrypto.
+
+use aes_gcm::aead::{
+    stream::{DecryptorBE32, EncryptorBE32},
+    OsRng,
+};
+use aes_gcm::Aes256Gcm;
+use rand::RngCore;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::crypto::CryptoError;
+
+/// Format version byte at the start of every stream
+pub const STREAM_VERSION: u8 = 1;
+/// Plaintext bytes per chunk
+pub const CHUNK_SIZE: usize = 64 * 1024;
+/// AES-GCM nonce minus STREAM's 5 bytes of counter and flag
+pub const NONCE_PREFIX_LEN: usize = 7;
+const TAG_LEN: usize = 16;
+const HEADER_LEN: usize = 1 + NONCE_PREFIX_LEN;
+
+/// Why a stream couldn't be encrypted or decrypted
+#[derive(Debug)]
+pub enum StreamError {
+    Io(std::io::Error),
+    Crypto(CryptoError),
+}
+
+impl std::fmt::Display for StreamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StreamError::Io(e) => write!(f, "io error: {}", e),
+            StreamError::Crypto(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for StreamError {}
+
+impl From<std::io::Error> for StreamError {
+    fn from(e: std::io::Error) -> Self {
+        StreamError::Io(e)
+    }
+}
+
+impl From<CryptoError> for StreamError {
+    fn from(e: CryptoError) -> Self {
+        StreamError::Crypto(e)
+    }
+}
+
+/// Size of the encrypted stream for `plaintext_len` bytes of input
+pub fn encrypted_len(plaintext_len: u64) -> u64 {
+    let chunks = plaintext_len.div_ceil(CHUNK_SIZE as u64).max(1);
+    HEADER_LEN as u64 + plaintext_len + chunks * TAG_LEN as u64
+}
+
+/// Encrypt everything `reader` yields under `key` into `writer`, holding at most
+/// two chunks in memory. Returns the number of plaintext bytes read. `key` must
+/// be fresh for every stream (a random per-file content key).
+pub async fn encrypt_stream<R, W>(key: &[u8; 32], mut reader: R, mut writer: W) -> Result<u64, StreamError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut prefix = [0u8; NONCE_PREFIX_LEN];
+    OsRng.fill_bytes(&mut prefix);
+    writer.write_all(&[STREAM_VERSION]).await?;
+    writer.write_all(&prefix).await?;
+
+    let mut encryptor = EncryptorBE32::<Aes256Gcm>::new(key.into(), prefix.as_slice().into());
+    let mut chunk = Vec::with_capacity(CHUNK_SIZE + TAG_LEN);
+    let mut next = Vec::with_capacity(CHUNK_SIZE + TAG_LEN);
+    let mut total = read_chunk(&mut reader, &mut chunk, CHUNK_SIZE).await?;
+    // Read one chunk ahead: only an empty read says the current chunk is the last
+    while read_chunk(&mut reader, &mut next, CHUNK_SIZE).await? > 0 {
+        encryptor.encrypt_next_in_place(b"", &mut chunk).map_err(|_| CryptoError::MessageTooLong)?;
+        writer.write_all(&chunk).await?;
+        total += next.len() as u64;
+        std::mem::swap(&mut chunk, &mut next);
+    }
+    encryptor.encrypt_last_in_place(b"", &mut chunk).map_err(|_| CryptoError::MessageTooLong)?;
+    writer.write_all(&chunk).await?;
+    writer.flush().await?;
+    Ok(total)
+}
+
+/// Decrypt a stream made by `encrypt_stream` into `writer`. Returns the number of
+/// plaintext bytes written. Chunks are written as they authenticate, so on an
+/// error `writer` holds a prefix of the plaintext and must be thrown away; the
+/// output is only complete and authentic once this returns Ok.
+pub async fn decrypt_stream<R, W>(key: &[u8; 32], mut reader: R, mut writer: W) -> Result<u64, StreamError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut header = [0u8; HEADER_LEN];
+    match reader.read_exact(&mut header).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Err(CryptoError::BadCiphertext.into()),
+        Err(e) => return Err(e.into()),
+    }
+    if header[0] != STREAM_VERSION {
+        return Err(CryptoError::BadCiphertext.into());
+    }
+
+    let mut decryptor = DecryptorBE32::<Aes256Gcm>::new(key.into(), header[1..].into());
+    let mut chunk = Vec::with_capacity(CHUNK_SIZE + TAG_LEN);
+    let mut next = Vec::with_capacity(CHUNK_SIZE + TAG_LEN);
+    let mut total = 0u64;
+    read_chunk(&mut reader, &mut chunk, CHUNK_SIZE + TAG_LEN).await?;
+    while read_chunk(&mut reader, &mut next, CHUNK_SIZE + TAG_LEN).await? > 0 {
+        decryptor.decrypt_next_in_place(b"", &mut chunk).map_err(|_| CryptoError::AuthFailed)?;
+        writer.write_all(&chunk).await?;
+        total += chunk.len() as u64;
+        std::mem::swap(&mut chunk, &mut next);
+    }
+    if chunk.len() < TAG_LEN {
+        return Err(CryptoError::BadCiphertext.into());
+    }
+    decryptor.decrypt_last_in_place(b"", &mut chunk).map_err(|_| CryptoError::AuthFailed)?;
+    writer.write_all(&chunk).await?;
+    writer.flush().await?;
+    Ok(total + chunk.len() as u64)
+}
+
+/// Replace `buf` with the next `len` bytes of `reader`, or fewer at end of input
+async fn read_chunk<R: AsyncRead + Unpin>(reader: &mut R, buf: &mut Vec<u8>, len: usize) -> std::io::Result<u64> {
+    buf.clear();
+    reader.take(len as u64).read_to_end(buf).await.map(|n| n as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    use base64::Engine;
+    use serde_json::Value;
+    use sha2::{Digest, Sha256};
+
+    use super::*;
+
+    /// Written by testdata/attachment_js.mjs
+    const ATTACHMENT_JS_FIXTURE: &str = include_str!("testdata/attachment_js.json");
+
+    fn b64(value: &Value) -> Vec<u8> {
+        base64::engine::general_purpose::STANDARD.decode(value.as_str().unwrap()).unwrap()
+    }
+
+    /// The file contents attachment_js.mjs encrypts
+    fn test_file(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i % 251) as u8).collect()
+    }
+
+    async fn encrypt(key: &[u8; 32], plaintext: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        assert_eq!(encrypt_stream(key, plaintext, &mut out).await.unwrap(), plaintext.len() as u64);
+        assert_eq!(out.len() as u64, encrypted_len(plaintext.len() as u64));
+        out
+    }
+
+    async fn decrypt(key: &[u8; 32], ciphertext: &[u8]) -> Result<Vec<u8>, StreamError> {
+        let mut out = Vec::new();
+        decrypt_stream(key, ciphertext, &mut out).await.map(|_| out)
+    }
+
+    #[tokio::test]
+    async fn round_trips_at_chunk_boundaries() {
+        let key = [7u8; 32];
+        for len in [0, 1, CHUNK_SIZE - 1, CHUNK_SIZE, CHUNK_SIZE + 1, 3 * CHUNK_SIZE] {
+            let plaintext: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let ciphertext = encrypt(&key, &plaintext).await;
+            assert_eq!(decrypt(&key, &ciphertext).await.unwrap(), plaintext);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_tampering_truncation_and_reordering() {
+        let key = [9u8; 32];
+        let plaintext = vec![1u8; 2 * CHUNK_SIZE + 10];
+        let ciphertext = encrypt(&key, &plaintext).await;
+        let chunk = CHUNK_SIZE + TAG_LEN;
+
+        assert!(matches!(decrypt(&[8u8; 32], &ciphertext).await, Err(StreamError::Crypto(CryptoError::AuthFailed))));
+
+        let mut flipped = ciphertext.clone();
+        flipped[HEADER_LEN + 5] ^= 1;
+        assert!(decrypt(&key, &flipped).await.is_err());
+
+        // Dropping the last chunk leaves a full chunk that wasn't sealed as last
+        assert!(decrypt(&key, &ciphertext[..HEADER_LEN + 2 * chunk]).await.is_err());
+        assert!(decrypt(&key, &ciphertext[..HEADER_LEN + 3]).await.is_err());
+        assert!(matches!(decrypt(&key, &ciphertext[..4]).await, Err(StreamError::Crypto(CryptoError::BadCiphertext))));
+
+        let mut swapped = ciphertext[..HEADER_LEN].to_vec();
+        swapped.extend_from_slice(&ciphertext[HEADER_LEN + chunk..HEADER_LEN + 2 * chunk]);
+        swapped.extend_from_slice(&ciphertext[HEADER_LEN..HEADER_LEN + chunk]);
+        swapped.extend_from_slice(&ciphertext[HEADER_LEN + 2 * chunk..]);
+        assert!(decrypt(&key, &swapped).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn opens_chat_html_attachments() {
+        let fixture: Value = serde_json::from_str(ATTACHMENT_JS_FIXTURE).unwrap();
+        for attachment in fixture["attachments"].as_array().unwrap() {
+            let meta = &attachment["meta"];
+            let key: [u8; 32] = b64(&meta["key"]).try_into().unwrap();
+            let plaintext = decrypt(&key, &b64(&attachment["blob"])).await.unwrap();
+            assert_eq!(plaintext, test_file(meta["size"].as_u64().unwrap() as usize));
+            assert_eq!(format!("{:x}", Sha256::digest(&plaintext)), meta["sha256"].as_str().unwrap());
+        }
+    }
+
+    /// chat.html's decryptAttachment opening what `encrypt_stream` wrote. Needs
+    /// Node, so it only runs with `cargo test -- --ignored`.
+    #[tokio::test]
+    #[ignore = "needs node; run with cargo test -- --ignored"]
+    async fn chat_html_opens_rust_attachments() {
+        let script = concat!(env!("CARGO_MANIFEST_DIR"), "/src/crypto/testdata/attachment_js.mjs");
+        let engine = base64::engine::general_purpose::STANDARD;
+        let key = [3u8; 32];
+        for size in [0, 100, CHUNK_SIZE, 2 * CHUNK_SIZE + 100] {
+            let plaintext = test_file(size);
+            let meta = serde_json::json!({
+                "size": size,
+                "sha256": format!("{:x}", Sha256::digest(&plaintext)),
+                "key": engine.encode(key),
+            });
+            let input = serde_json::json!({ "blob": engine.encode(encrypt(&key, &plaintext).await), "meta": meta });
+            let mut node = Command::new("node").arg(script).stdin(Stdio::piped()).stdout(Stdio::piped()).spawn().expect("node must be installed to run this test");
+            node.stdin.take().unwrap().write_all(input.to_string().as_bytes()).unwrap();
+            let output = node.wait_with_output().unwrap();
+            assert!(output.status.success(), "attachment_js.mjs failed for {} bytes", size);
+            let output: Value = serde_json::from_slice(&output.stdout).unwrap();
+            assert_eq!(b64(&output["plain"]), plaintext);
+        }
+    }
+}
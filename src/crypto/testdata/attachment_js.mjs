@@ -0,0 +1,42 @@
+// Drives the attachment code in static/chat.html (encryptAttachment and
+// decryptAttachment, taken verbatim from the page) from Node for the tests in
+// src/crypto/stream.rs.
+//
+//   node attachment_js.mjs fixture    print a fresh attachment_js.json: files of
+//                                     a few sizes (bytes i % 251) as chat.html
+//                                     encrypts them, with their message metadata
+//   node attachment_js.mjs < in.json  read { blob, meta } (blob base64), decrypt
+//                                     it with decryptAttachment, print { plain }
+import fs from 'fs';
+
+globalThis.window = globalThis;
+const page = fs.readFileSync(new URL('../../../static/chat.html', import.meta.url), 'utf8');
+const start = page.indexOf('// Attachments: each file');
+const end = page.indexOf('// Upload the encrypted bytes');
+if (start < 0 || end < start) throw new Error('attachment code not found in chat.html');
+
+const b64 = bytes => Buffer.from(bytes).toString('base64');
+const unb64 = s => new Uint8Array(Buffer.from(s, 'base64'));
+const concatBytes = (...parts) => new Uint8Array(Buffer.concat(parts.map(p => Buffer.from(p))));
+const { encryptAttachment, decryptAttachment } = new Function('bytesToBase64', 'base64ToBytes', 'concatBytes',
+  `${page.slice(start, end)}\nreturn { encryptAttachment, decryptAttachment };`)(b64, unb64, concatBytes);
+
+// Just enough of a File for encryptAttachment
+function file(name, size) {
+  const bytes = Uint8Array.from({ length: size }, (_, i) => i % 251);
+  return { name, size, type: '', arrayBuffer: async () => bytes.buffer };
+}
+
+if (process.argv[2] === 'fixture') {
+  // Empty, a single short chunk, and a full chunk plus a short last one
+  const attachments = [];
+  for (const size of [0, 100, 64 * 1024 + 100]) {
+    const { blob, meta } = await encryptAttachment(file(`file-${size}.bin`, size));
+    attachments.push({ meta, blob: b64(blob) });
+  }
+  console.log(JSON.stringify({ attachments }, null, 2));
+} else {
+  const input = JSON.parse(fs.readFileSync(0, 'utf8'));
+  const plain = await decryptAttachment(unb64(input.blob), input.meta);
+  console.log(JSON.stringify({ plain: b64(plain) }));
+}
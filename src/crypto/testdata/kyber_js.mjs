@@ -0,0 +1,99 @@
+// Drives static/kyber.js (the browser's Kyber) from Node for the tests in
+// src/crypto/kyber.rs.
+//
+//   node kyber_js.mjs fixture   print a fresh kyber_js.json: a kyber.js keypair,
+//                               a kyber.js encapsulation, and two envelopes in
+//                               the browser's slot format (kem 1 and kem 2)
+//   node kyber_js.mjs < in.json read { pk, sk, ct } (base64), decapsulate ct with
+//                               sk and encapsulate to pk with kyber.js, print
+//                               { ss, js_ct, js_ss }
+//
+// The envelope code mirrors sealForDevices/messageContext/hybridSecret in
+// static/chat.html.
+import fs from 'fs';
+
+globalThis.window = globalThis;
+(0, eval)(fs.readFileSync(new URL('../../../static/kyber.js', import.meta.url), 'utf8'));
+const { kyber } = globalThis;
+const subtle = globalThis.crypto.subtle;
+
+const b64 = bytes => Buffer.from(bytes).toString('base64');
+const unb64 = s => new Uint8Array(Buffer.from(s, 'base64'));
+const concat = (...parts) => new Uint8Array(Buffer.concat(parts.map(p => Buffer.from(p))));
+
+async function hybridSecret(xSs, kyberSs, epk, recipientXPk, kyberCt) {
+  const transcript = new Uint8Array(await subtle.digest('SHA-256', concat(epk, recipientXPk, kyberCt)));
+  const info = concat(new TextEncoder().encode('noid-hybrid-kem-v1'), transcript);
+  const key = await subtle.importKey('raw', concat(xSs, kyberSs), 'HKDF', false, ['deriveBits']);
+  return new Uint8Array(await subtle.deriveBits({ name: 'HKDF', hash: 'SHA-256', salt: new Uint8Array(), info }, key, 256));
+}
+
+async function x25519(privateKey, publicBytes) {
+  const pub = await subtle.importKey('raw', publicBytes, { name: 'X25519' }, false, []);
+  return new Uint8Array(await subtle.deriveBits({ name: 'X25519', public: pub }, privateKey, 256));
+}
+
+// kdf::MESSAGE_VERSION
+const MESSAGE_VERSION = 3;
+
+async function messageContext(sender, recipient, kem, kemCt) {
+  const enc = new TextEncoder();
+  const digest = new Uint8Array(await subtle.digest('SHA-256', kemCt));
+  const parts = [enc.encode(sender), enc.encode(recipient), digest].flatMap(p => {
+    const len = new Uint8Array(4);
+    new DataView(len.buffer).setUint32(0, p.length);
+    return [len, p];
+  });
+  return concat(enc.encode('noid-message'), [MESSAGE_VERSION, kem], ...parts);
+}
+
+async function seal(text, from, to, pk, xPk) {
+  const contentKeyBytes = crypto.getRandomValues(new Uint8Array(32));
+  const contentKey = await subtle.importKey('raw', contentKeyBytes, 'AES-GCM', false, ['encrypt']);
+  const nonce = crypto.getRandomValues(new Uint8Array(12));
+  const ciphertext = await subtle.encrypt({ name: 'AES-GCM', iv: nonce }, contentKey, new TextEncoder().encode(text));
+  const [kyberCt, kyberSs] = kyber.Encrypt1024(pk).map(a => new Uint8Array(a));
+  let sharedSecret = kyberSs;
+  let kemCt = kyberCt;
+  const slot = { kem: 1 };
+  if (xPk) {
+    const eph = await subtle.generateKey({ name: 'X25519' }, true, ['deriveBits']);
+    const epk = new Uint8Array(await subtle.exportKey('raw', eph.publicKey));
+    sharedSecret = await hybridSecret(await x25519(eph.privateKey, xPk), kyberSs, epk, xPk, kyberCt);
+    Object.assign(slot, { kem: 2, x25519_epk: b64(epk) });
+    kemCt = concat(epk, kyberCt);
+  }
+  const context = await messageContext(from, to, slot.kem, kemCt);
+  const ikm = await subtle.importKey('raw', sharedSecret, 'HKDF', false, ['deriveKey']);
+  const wrapKey = await subtle.deriveKey({ name: 'HKDF', hash: 'SHA-256', salt: new Uint8Array(), info: context },
+    ikm, { name: 'AES-GCM', length: 256 }, false, ['encrypt']);
+  const wrapped = await subtle.encrypt({ name: 'AES-GCM', iv: nonce, additionalData: context }, wrapKey, contentKeyBytes);
+  Object.assign(slot, { kyber_ct: b64(kyberCt), wrapped_key: b64(new Uint8Array(wrapped)) });
+  return { plaintext: text, from, to, nonce: b64(nonce), ciphertext: b64(new Uint8Array(ciphertext)), slot };
+}
+
+if (process.argv[2] === 'fixture') {
+  const [pk, sk] = kyber.KeyGen1024();
+  const [ct, ss] = kyber.Encrypt1024(pk);
+  const x = await subtle.generateKey({ name: 'X25519' }, true, ['deriveBits']);
+  const xPk = new Uint8Array(await subtle.exportKey('raw', x.publicKey));
+  const xSk = Buffer.from((await subtle.exportKey('jwk', x.privateKey)).d, 'base64url');
+  const fixture = {
+    pk: b64(pk),
+    sk: b64(sk),
+    ct: b64(ct),
+    ss: b64(ss),
+    x25519_pk: b64(xPk),
+    x25519_sk: b64(xSk),
+    envelopes: [
+      await seal('hello from kyber.js', 'alice', 'bob', pk),
+      await seal('hello from kyber.js, hybrid', 'alice', 'bob', pk, xPk),
+    ],
+  };
+  console.log(JSON.stringify(fixture, null, 2));
+} else {
+  const input = JSON.parse(fs.readFileSync(0, 'utf8'));
+  const ss = kyber.Decrypt1024(Array.from(unb64(input.ct)), Array.from(unb64(input.sk)));
+  const [jsCt, jsSs] = kyber.Encrypt1024(Array.from(unb64(input.pk)));
+  console.log(JSON.stringify({ ss: b64(ss), js_ct: b64(jsCt), js_ss: b64(jsSs) }));
+}
@@ -0,0 +1,113 @@
+// src/db.rs
+use std::sync::{Arc, Mutex};
+use rusqlite::Connection;
+
+/// Shared SQLite handle. A rusqlite `Connection` is not `Sync`, so callers take
+/// the lock for one query at a time and never hold it across an `.await`.
+pub type Db = Arc<Mutex<Connection>>;
+
+/// Database file path (override with NOID_DB_PATH)
+pub fn db_path() -> String {
+    std::env::var("NOID_DB_PATH").unwrap_or_else(|_| "chat.db".to_string())
+}
+
+/// Open (or create) the database and make sure all tables exist
+pub fn open(path: &str) -> rusqlite::Result<Db> {
+    let conn = Connection::open(path)?;
+    init_schema(&conn)?;
+    Ok(Arc::new(Mutex::new(conn)))
+}
+
+fn init_schema(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS users (
+            username TEXT PRIMARY KEY,
+            password_hash TEXT NOT NULL,
+            created_at INTEGER NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS sessions (
+            token_hash TEXT PRIMARY KEY,
+            username TEXT NOT NULL,
+            created_at INTEGER NOT NULL,
+            last_seen INTEGER NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS pubkeys (
+            key_id TEXT NOT NULL,
+            username TEXT NOT NULL,
+            pubkey TEXT NOT NULL,
+            fingerprint TEXT NOT NULL,
+            created_at INTEGER NOT NULL,
+            published_at INTEGER NOT NULL,
+            revoked_at INTEGER,
+            device_id TEXT NOT NULL DEFAULT '',
+            x25519_pubkey TEXT,
+            sig_pubkey TEXT,
+            kem INTEGER NOT NULL DEFAULT 1,
+            PRIMARY KEY (username, key_id)
+        );
+        CREATE TABLE IF NOT EXISTS server_keys (
+            name TEXT PRIMARY KEY,
+            secret BLOB NOT NULL,
+            created_at INTEGER NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS offline_queue (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            recipient TEXT NOT NULL,
+            envelope TEXT NOT NULL,
+            created_at INTEGER NOT NULL,
+            expires_at INTEGER NOT NULL,
+            device_id TEXT NOT NULL DEFAULT ''
+        );
+        CREATE INDEX IF NOT EXISTS offline_queue_recipient ON offline_queue (recipient, id);
+        CREATE TABLE IF NOT EXISTS routed_messages (
+            mid TEXT NOT NULL,
+            sender TEXT NOT NULL,
+            recipient TEXT NOT NULL,
+            expires_at INTEGER NOT NULL,
+            PRIMARY KEY (mid, sender, recipient)
+        );
+        CREATE TABLE IF NOT EXISTS chat_groups (
+            id TEXT PRIMARY KEY,
+            name TEXT NOT NULL,
+            owner TEXT NOT NULL,
+            created_at INTEGER NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS group_members (
+            group_id TEXT NOT NULL,
+            username TEXT NOT NULL,
+            added_by TEXT NOT NULL,
+            joined_at INTEGER NOT NULL,
+            PRIMARY KEY (group_id, username)
+        );
+        CREATE INDEX IF NOT EXISTS group_members_user ON group_members (username);",
+    )?;
+    // Columns added after the first release; CREATE TABLE IF NOT EXISTS won't add
+    // them to an existing database
+    add_column_if_missing(conn, "pubkeys", "device_id", "TEXT NOT NULL DEFAULT ''")?;
+    add_column_if_missing(conn, "offline_queue", "device_id", "TEXT NOT NULL DEFAULT ''")?;
+    add_column_if_missing(conn, "pubkeys", "x25519_pubkey", "TEXT")?;
+    add_column_if_missing(conn, "pubkeys", "sig_pubkey", "TEXT")?;
+    add_column_if_missing(conn, "pubkeys", "kem", "INTEGER NOT NULL DEFAULT 1")?;
+    Ok(())
+}
+
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, decl: &str) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let exists = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<rusqlite::Result<Vec<_>>>()?
+        .iter()
+        .any(|name| name == column);
+    if !exists {
+        conn.execute_batch(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, decl))?;
+    }
+    Ok(())
+}
+
+/// Current time as unix seconds (what we store in every timestamp column)
+pub fn unix_now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
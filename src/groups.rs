@@ -0,0 +1,321 @@
+// src/groups.rs
+// Group conversations. Membership lives in SQLite; the relay fans group envelopes
+// out to the current members, each member device getting only its own key slot,
+// so the server still never sees plaintext. The creator owns the group: any
+// member can invite, only the owner can kick. When the owner leaves, the longest
+// standing member takes over; the group is deleted when the last member leaves.
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+use crate::auth::normalize_username;
+use crate::db::{self, Db};
+
+/// Most members a group can have
+const MAX_GROUP_MEMBERS: usize = 256;
+/// Longest group name
+const MAX_GROUP_NAME_LEN: usize = 64;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Member {
+    pub username: String,
+    pub added_by: String,
+    pub joined_at: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GroupInfo {
+    pub id: String,
+    pub name: String,
+    pub owner: String,
+    pub created_at: i64,
+    /// Oldest member first
+    pub members: Vec<Member>,
+}
+
+impl GroupInfo {
+    pub fn member_names(&self) -> Vec<String> {
+        self.members.iter().map(|m| m.username.clone()).collect()
+    }
+}
+
+#[derive(Debug)]
+pub enum GroupError {
+    InvalidName(&'static str),
+    /// No such group, or the caller isn't in it
+    NotFound,
+    /// Only the owner may do this
+    NotOwner,
+    /// Target user isn't a member
+    NotMember,
+    AlreadyMember,
+    UnknownUser(String),
+    Full,
+    Db(rusqlite::Error),
+}
+
+impl std::fmt::Display for GroupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GroupError::InvalidName(msg) => write!(f, "{}", msg),
+            GroupError::NotFound => write!(f, "no such group"),
+            GroupError::NotOwner => write!(f, "only the group owner can do that"),
+            GroupError::NotMember => write!(f, "user is not a member"),
+            GroupError::AlreadyMember => write!(f, "user is already a member"),
+            GroupError::UnknownUser(u) => write!(f, "unknown user '{}'", u),
+            GroupError::Full => write!(f, "group is full ({} members)", MAX_GROUP_MEMBERS),
+            GroupError::Db(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for GroupError {
+    fn from(e: rusqlite::Error) -> Self {
+        GroupError::Db(e)
+    }
+}
+
+fn validate_name(name: &str) -> Result<(), GroupError> {
+    if name.is_empty() {
+        return Err(GroupError::InvalidName("group name is required"));
+    }
+    if name.chars().count() > MAX_GROUP_NAME_LEN {
+        return Err(GroupError::InvalidName("group name must be at most 64 characters"));
+    }
+    if name.chars().any(char::is_control) {
+        return Err(GroupError::InvalidName("group name can't contain control characters"));
+    }
+    Ok(())
+}
+
+fn account_exists(conn: &Connection, username: &str) -> rusqlite::Result<bool> {
+    conn.query_row("SELECT 1 FROM users WHERE username = ?1", params![username], |_| Ok(()))
+        .optional()
+        .map(|found| found.is_some())
+}
+
+fn member_exists(conn: &Connection, group_id: &str, username: &str) -> rusqlite::Result<bool> {
+    conn.query_row(
+        "SELECT 1 FROM group_members WHERE group_id = ?1 AND username = ?2",
+        params![group_id, username],
+        |_| Ok(()),
+    )
+    .optional()
+    .map(|found| found.is_some())
+}
+
+fn load(conn: &Connection, group_id: &str) -> rusqlite::Result<Option<GroupInfo>> {
+    let group = conn
+        .query_row(
+            "SELECT id, name, owner, created_at FROM chat_groups WHERE id = ?1",
+            params![group_id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        )
+        .optional()?;
+    let Some((id, name, owner, created_at)) = group else {
+        return Ok(None);
+    };
+    let mut stmt = conn.prepare(
+        "SELECT username, added_by, joined_at FROM group_members WHERE group_id = ?1
+         ORDER BY joined_at, rowid",
+    )?;
+    let members = stmt
+        .query_map(params![group_id], |row| {
+            Ok(Member { username: row.get(0)?, added_by: row.get(1)?, joined_at: row.get(2)? })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(Some(GroupInfo { id, name, owner, created_at, members }))
+}
+
+/// Load a group the caller belongs to (NotFound otherwise, so non-members can't
+/// probe which groups exist)
+fn load_for_member(conn: &Connection, group_id: &str, caller: &str) -> Result<GroupInfo, GroupError> {
+    match load(conn, group_id)? {
+        Some(group) if group.members.iter().any(|m| m.username == caller) => Ok(group),
+        _ => Err(GroupError::NotFound),
+    }
+}
+
+/// Create a group owned by `owner` with the given initial members
+pub fn create_group(db: &Db, owner: &str, name: &str, members: &[String]) -> Result<GroupInfo, GroupError> {
+    let name = name.trim();
+    validate_name(name)?;
+    let mut invited: Vec<String> = Vec::new();
+    for member in members.iter().map(|m| normalize_username(m)) {
+        if member != owner && !invited.contains(&member) {
+            invited.push(member);
+        }
+    }
+    if invited.len() + 1 > MAX_GROUP_MEMBERS {
+        return Err(GroupError::Full);
+    }
+
+    let id = uuid::Uuid::new_v4().to_string();
+    let now = db::unix_now();
+    let mut conn = db.lock().unwrap();
+    let tx = conn.transaction()?;
+    for member in &invited {
+        if !account_exists(&tx, member)? {
+            return Err(GroupError::UnknownUser(member.clone()));
+        }
+    }
+    tx.execute(
+        "INSERT INTO chat_groups (id, name, owner, created_at) VALUES (?1, ?2, ?3, ?4)",
+        params![id, name, owner, now],
+    )?;
+    for member in std::iter::once(owner).chain(invited.iter().map(String::as_str)) {
+        tx.execute(
+            "INSERT INTO group_members (group_id, username, added_by, joined_at) VALUES (?1, ?2, ?3, ?4)",
+            params![id, member, owner, now],
+        )?;
+    }
+    tx.commit()?;
+    load(&conn, &id)?.ok_or(GroupError::NotFound)
+}
+
+/// A group the caller is a member of
+pub fn group_info(db: &Db, group_id: &str, caller: &str) -> Result<GroupInfo, GroupError> {
+    let conn = db.lock().unwrap();
+    load_for_member(&conn, group_id, caller)
+}
+
+/// Every group `username` is in, oldest first
+pub fn groups_for_user(db: &Db, username: &str) -> rusqlite::Result<Vec<GroupInfo>> {
+    let conn = db.lock().unwrap();
+    let ids = {
+        let mut stmt = conn.prepare(
+            "SELECT g.id FROM chat_groups g JOIN group_members m ON m.group_id = g.id
+             WHERE m.username = ?1 ORDER BY g.created_at, g.rowid",
+        )?;
+        let rows = stmt.query_map(params![username], |row| row.get::<_, String>(0))?;
+        rows.collect::<rusqlite::Result<Vec<_>>>()?
+    };
+    let mut groups = Vec::with_capacity(ids.len());
+    for id in ids {
+        if let Some(group) = load(&conn, &id)? {
+            groups.push(group);
+        }
+    }
+    Ok(groups)
+}
+
+/// Current members of a group (empty if it doesn't exist)
+pub fn members(db: &Db, group_id: &str) -> rusqlite::Result<Vec<String>> {
+    let conn = db.lock().unwrap();
+    let mut stmt = conn.prepare("SELECT username FROM group_members WHERE group_id = ?1 ORDER BY joined_at, rowid")?;
+    let rows = stmt.query_map(params![group_id], |row| row.get(0))?;
+    rows.collect()
+}
+
+/// Add `username` to a group `by` is a member of
+pub fn invite(db: &Db, group_id: &str, by: &str, username: &str) -> Result<GroupInfo, GroupError> {
+    let username = normalize_username(username);
+    let conn = db.lock().unwrap();
+    let group = load_for_member(&conn, group_id, by)?;
+    if group.members.iter().any(|m| m.username == username) {
+        return Err(GroupError::AlreadyMember);
+    }
+    if group.members.len() >= MAX_GROUP_MEMBERS {
+        return Err(GroupError::Full);
+    }
+    if !account_exists(&conn, &username)? {
+        return Err(GroupError::UnknownUser(username));
+    }
+    conn.execute(
+        "INSERT INTO group_members (group_id, username, added_by, joined_at) VALUES (?1, ?2, ?3, ?4)",
+        params![group_id, username, by, db::unix_now()],
+    )?;
+    load(&conn, group_id)?.ok_or(GroupError::NotFound)
+}
+
+/// Remove `username` from the group, handing ownership on or deleting the group
+/// as needed. Returns what's left of the group (None if it was deleted).
+fn remove_member(conn: &Connection, group: &GroupInfo, username: &str) -> rusqlite::Result<Option<GroupInfo>> {
+    conn.execute(
+        "DELETE FROM group_members WHERE group_id = ?1 AND username = ?2",
+        params![group.id, username],
+    )?;
+    let next_owner = group.members.iter().find(|m| m.username != username).map(|m| m.username.as_str());
+    match next_owner {
+        None => {
+            conn.execute("DELETE FROM chat_groups WHERE id = ?1", params![group.id])?;
+            Ok(None)
+        }
+        Some(next) => {
+            if group.owner == username {
+                conn.execute("UPDATE chat_groups SET owner = ?1 WHERE id = ?2", params![next, group.id])?;
+            }
+            load(conn, &group.id)
+        }
+    }
+}
+
+/// The caller leaves the group. Returns the remaining group (None if deleted).
+pub fn leave(db: &Db, group_id: &str, username: &str) -> Result<Option<GroupInfo>, GroupError> {
+    let conn = db.lock().unwrap();
+    let group = load_for_member(&conn, group_id, username)?;
+    Ok(remove_member(&conn, &group, username)?)
+}
+
+/// The owner removes another member
+pub fn kick(db: &Db, group_id: &str, by: &str, username: &str) -> Result<GroupInfo, GroupError> {
+    let username = normalize_username(username);
+    let conn = db.lock().unwrap();
+    let group = load_for_member(&conn, group_id, by)?;
+    if group.owner != by {
+        return Err(GroupError::NotOwner);
+    }
+    if username == by || !member_exists(&conn, group_id, &username)? {
+        return Err(GroupError::NotMember);
+    }
+    remove_member(&conn, &group, &username)?.ok_or(GroupError::NotFound)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn db_with_users(names: &[&str]) -> Db {
+        let db = db::open(":memory:").unwrap();
+        for name in names {
+            db.lock()
+                .unwrap()
+                .execute("INSERT INTO users (username, password_hash, created_at) VALUES (?1, 'x', 0)", params![name])
+                .unwrap();
+        }
+        db
+    }
+
+    #[test]
+    fn only_members_see_and_invite_and_only_the_owner_kicks() {
+        let db = db_with_users(&["alice", "bob", "carol", "mallory"]);
+        let group = create_group(&db, "alice", " team ", &["Bob".to_string(), "alice".to_string()]).unwrap();
+        assert_eq!(group.name, "team");
+        assert_eq!(group.member_names(), vec!["alice", "bob"]);
+
+        assert!(matches!(group_info(&db, &group.id, "mallory"), Err(GroupError::NotFound)));
+        assert!(matches!(invite(&db, &group.id, "mallory", "mallory"), Err(GroupError::NotFound)));
+        assert!(matches!(leave(&db, &group.id, "mallory"), Err(GroupError::NotFound)));
+        assert!(matches!(invite(&db, &group.id, "bob", "alice"), Err(GroupError::AlreadyMember)));
+        assert!(matches!(invite(&db, &group.id, "bob", "nobody"), Err(GroupError::UnknownUser(_))));
+        assert_eq!(invite(&db, &group.id, "bob", "carol").unwrap().member_names(), vec!["alice", "bob", "carol"]);
+
+        assert!(matches!(kick(&db, &group.id, "bob", "carol"), Err(GroupError::NotOwner)));
+        assert!(matches!(kick(&db, &group.id, "alice", "mallory"), Err(GroupError::NotMember)));
+        assert!(matches!(kick(&db, &group.id, "alice", "alice"), Err(GroupError::NotMember)));
+        kick(&db, &group.id, "alice", "carol").unwrap();
+        assert_eq!(members(&db, &group.id).unwrap(), vec!["alice", "bob"]);
+        assert!(groups_for_user(&db, "carol").unwrap().is_empty());
+    }
+
+    #[test]
+    fn ownership_passes_on_and_the_last_member_deletes_the_group() {
+        let db = db_with_users(&["alice", "bob"]);
+        assert!(matches!(create_group(&db, "alice", "x", &["ghost".to_string()]), Err(GroupError::UnknownUser(_))));
+        assert!(matches!(create_group(&db, "alice", "  ", &[]), Err(GroupError::InvalidName(_))));
+
+        let group = create_group(&db, "alice", "pair", &["bob".to_string()]).unwrap();
+        let left = leave(&db, &group.id, "alice").unwrap().unwrap();
+        assert_eq!(left.owner, "bob");
+        assert!(leave(&db, &group.id, "bob").unwrap().is_none());
+        assert!(members(&db, &group.id).unwrap().is_empty());
+    }
+}
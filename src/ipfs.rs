@@ -1,27 +1,517 @@
 // src/ipfs.rs
-use anyhow::Result;
-use reqwest::Client;
-use reqwest::multipart;
-
-/// POST bytes to local IPFS node and return CID string
-pub async fn add_bytes_to_ipfs(bytes: Vec<u8>) -> Result<String, anyhow::Error> {
-    let client = Client::new();
-    let part = multipart::Part::bytes(bytes).file_name("upload.bin");
-    let form = multipart::Form::new().part("file", part);
-
-    // expects ipfs daemon at http://127.0.0.1:5001
-    let res = client.post("http://127.0.0.1:5001/api/v0/add")
-        .multipart(form)
-        .send()
-        .await?;
-
-    let text = res.text().await?;
-    // The API returns lines like: {"Name":"upload.bin","Hash":"Qm...","Size":"123"}
-    // We'll parse JSON to get the Hash/CID
-    let json: serde_json::Value = serde_json::from_str(&text)?;
-    if let Some(hash) = json.get("Hash").and_then(|v| v.as_str()) {
-        Ok(hash.to_string())
-    } else {
-        Err(anyhow::anyhow!("unexpected ipfs response: {}", text))
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use axum::body::Bytes;
+use futures::channel::mpsc;
+use futures::{SinkExt, Stream, StreamExt};
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use reqwest::{multipart, Client, RequestBuilder};
+
+/// Pieces uploads are forwarded to the node in; bigger body frames are split so
+/// memory per upload stays bounded and progress moves in even steps
+pub const UPLOAD_CHUNK: usize = 64 * 1024;
+/// How many chunks may wait between the client and the node before we stop
+/// reading from the client
+pub const UPLOAD_QUEUE: usize = 8;
+
+/// Connection settings for the IPFS daemon's HTTP API
+#[derive(Debug, Clone)]
+pub struct IpfsConfig {
+    /// Base URL of the API (NOID_IPFS_API, default http://127.0.0.1:5001)
+    pub api_url: String,
+    /// Sent as the `Authorization` header, for daemons behind an authenticating
+    /// proxy (NOID_IPFS_AUTH, e.g. "Basic dXNlcjpwYXNz")
+    pub auth_header: Option<String>,
+    /// Limit on establishing a connection
+    pub connect_timeout: Duration,
+    /// Limit on a whole request, body transfer included (NOID_IPFS_TIMEOUT_SECS,
+    /// default 300)
+    pub timeout: Duration,
+    /// Extra attempts after the daemon couldn't be reached (NOID_IPFS_RETRIES,
+    /// default 3)
+    pub retries: u32,
+    /// Wait before the first retry; doubles with every further one
+    pub backoff: Duration,
+    /// Largest upload /ipfs/add accepts, in bytes (NOID_IPFS_MAX_UPLOAD, default
+    /// 64 MiB)
+    pub max_upload: u64,
+}
+
+impl Default for IpfsConfig {
+    fn default() -> Self {
+        IpfsConfig {
+            api_url: "http://127.0.0.1:5001".to_string(),
+            auth_header: None,
+            connect_timeout: Duration::from_secs(5),
+            timeout: Duration::from_secs(300),
+            retries: 3,
+            backoff: Duration::from_millis(250),
+            max_upload: 64 * 1024 * 1024,
+        }
+    }
+}
+
+impl IpfsConfig {
+    /// Defaults, overridden by the NOID_IPFS_* environment variables
+    pub fn from_env() -> Self {
+        fn var<T: std::str::FromStr>(name: &str) -> Option<T> {
+            std::env::var(name).ok()?.parse().ok()
+        }
+        let defaults = IpfsConfig::default();
+        IpfsConfig {
+            api_url: var("NOID_IPFS_API").unwrap_or(defaults.api_url),
+            auth_header: var("NOID_IPFS_AUTH").filter(|v: &String| !v.is_empty()),
+            timeout: var("NOID_IPFS_TIMEOUT_SECS").map(Duration::from_secs).unwrap_or(defaults.timeout),
+            retries: var("NOID_IPFS_RETRIES").unwrap_or(defaults.retries),
+            max_upload: var("NOID_IPFS_MAX_UPLOAD").unwrap_or(defaults.max_upload),
+            ..defaults
+        }
+    }
+}
+
+/// What went wrong talking to the daemon
+#[derive(Debug)]
+pub enum IpfsError {
+    /// No connection, or no answer in time (after retrying)
+    Unreachable(String),
+    /// The daemon answered, but with an error status or a body we can't use
+    BadResponse(String),
+}
+
+impl std::fmt::Display for IpfsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IpfsError::Unreachable(e) => write!(f, "ipfs daemon unreachable: {}", e),
+            IpfsError::BadResponse(e) => write!(f, "bad response from ipfs daemon: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for IpfsError {}
+
+impl From<reqwest::Error> for IpfsError {
+    /// Only failing to connect or timing out counts as unreachable (and gets
+    /// retried); a connection that broke off mid-request may already have
+    /// reached the daemon
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_connect() || e.is_timeout() {
+            IpfsError::Unreachable(e.to_string())
+        } else {
+            IpfsError::BadResponse(e.to_string())
+        }
+    }
+}
+
+/// Shared handle on the daemon's HTTP API. Cheap to clone; clones share one
+/// connection pool.
+#[derive(Clone)]
+pub struct IpfsClient {
+    http: Client,
+    config: Arc<IpfsConfig>,
+}
+
+impl IpfsClient {
+    pub fn new(mut config: IpfsConfig) -> Result<Self, anyhow::Error> {
+        config.api_url = config.api_url.trim_end_matches('/').to_string();
+        let mut headers = HeaderMap::new();
+        if let Some(auth) = &config.auth_header {
+            let mut value = HeaderValue::from_str(auth)?;
+            value.set_sensitive(true);
+            headers.insert(AUTHORIZATION, value);
+        }
+        let http = Client::builder()
+            .default_headers(headers)
+            .connect_timeout(config.connect_timeout)
+            .timeout(config.timeout)
+            .pool_idle_timeout(Duration::from_secs(90))
+            .build()?;
+        Ok(IpfsClient { http, config: Arc::new(config) })
+    }
+
+    pub fn max_upload_bytes(&self) -> u64 {
+        self.config.max_upload
+    }
+
+    /// Stream an upload to the daemon as it arrives and return its CID. Chunks
+    /// come from `rx`; an `Err` on it aborts the upload. Only attempts that
+    /// failed before the first chunk was sent are retried, since the body can't
+    /// be replayed.
+    pub async fn add_stream(&self, rx: UploadReceiver) -> Result<String, IpfsError> {
+        let slot = Arc::new(Mutex::new(Some(rx)));
+        let res = self
+            .send_with_retry("add", || {
+                let unsent = slot.lock().map(|rx| rx.is_some()).unwrap_or(false);
+                let body = LazyBody { slot: slot.clone(), rx: None };
+                let part = multipart::Part::stream(reqwest::Body::wrap_stream(body)).file_name("upload.bin");
+                let form = multipart::Form::new().part("file", part);
+                unsent.then(|| self.http.post(self.url("add")).multipart(form))
+            })
+            .await?;
+
+        let text = res.text().await?;
+        // The API returns lines like: {"Name":"upload.bin","Hash":"Qm...","Size":"123"}
+        let json: serde_json::Value = serde_json::from_str(&text).map_err(|_| IpfsError::BadResponse(text.clone()))?;
+        match json.get("Hash").and_then(|v| v.as_str()) {
+            Some(hash) => Ok(hash.to_string()),
+            None => Err(IpfsError::BadResponse(text)),
+        }
+    }
+
+    /// Ask the daemon for the content behind `cid`. Returns the response as soon
+    /// as the daemon starts answering, so the caller can stream the body.
+    pub async fn cat(&self, cid: &str) -> Result<reqwest::Response, IpfsError> {
+        self.send_with_retry("cat", || Some(self.http.post(self.url("cat")).query(&[("arg", cid)])))
+            .await
+    }
+
+    fn url(&self, command: &str) -> String {
+        format!("{}/api/v0/{}", self.config.api_url, command)
+    }
+
+    /// Send the request `build` makes, building and sending it again with
+    /// exponential backoff while the daemon is unreachable. `build` returns None
+    /// once the request can't be rebuilt. Error statuses are not retried.
+    async fn send_with_retry<F>(&self, command: &str, build: F) -> Result<reqwest::Response, IpfsError>
+    where
+        F: Fn() -> Option<RequestBuilder>,
+    {
+        let mut delay = self.config.backoff;
+        let mut attempt = 0;
+        loop {
+            let Some(request) = build() else {
+                return Err(IpfsError::Unreachable(format!("{} failed after its body was sent", command)));
+            };
+            let err = match request.send().await {
+                Ok(res) if res.status().is_success() => return Ok(res),
+                Ok(res) => {
+                    // Errors come back as {"Message":"...","Code":0,"Type":"error"}
+                    let status = res.status();
+                    let text = res.text().await.unwrap_or_default();
+                    return Err(IpfsError::BadResponse(format!("{} returned {}: {}", command, status, text.trim())));
+                }
+                Err(e) => IpfsError::from(e),
+            };
+            if !matches!(err, IpfsError::Unreachable(_)) || attempt >= self.config.retries {
+                return Err(err);
+            }
+            attempt += 1;
+            println!("[IPFS] {} attempt {} failed ({}), retrying in {:?}", command, attempt, err, delay);
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+        }
+    }
+}
+
+pub type UploadSender = mpsc::Sender<Result<Bytes, std::io::Error>>;
+pub type UploadReceiver = mpsc::Receiver<Result<Bytes, std::io::Error>>;
+
+/// Upload body that takes the receiver out of `slot` only when first polled, so
+/// an attempt that never got to send anything leaves it there for a retry
+struct LazyBody {
+    slot: Arc<Mutex<Option<UploadReceiver>>>,
+    rx: Option<UploadReceiver>,
+}
+
+impl Stream for LazyBody {
+    type Item = Result<Bytes, std::io::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.rx.is_none() {
+            self.rx = self.slot.lock().ok().and_then(|mut rx| rx.take());
+        }
+        match self.rx.as_mut() {
+            Some(rx) => rx.poll_next_unpin(cx),
+            None => Poll::Ready(Some(Err(std::io::Error::other("upload body already taken")))),
+        }
+    }
+}
+
+/// Why an upload didn't make it to the node
+#[derive(Debug)]
+pub enum UploadError {
+    /// More than the configured upload limit
+    TooLarge,
+    /// The client's body broke off or wasn't what the content type said
+    BadBody(String),
+    /// The node refused or couldn't be reached
+    Ipfs(IpfsError),
+}
+
+impl std::fmt::Display for UploadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UploadError::TooLarge => write!(f, "upload too large"),
+            UploadError::BadBody(e) => write!(f, "bad upload body: {}", e),
+            UploadError::Ipfs(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Copy a client's body into `tx` in pieces of at most `UPLOAD_CHUNK` bytes,
+/// stopping at `limit`. Returns the number of bytes forwarded. On failure the
+/// error is also pushed into `tx`, so the upload to the node is abandoned
+/// rather than finished with a truncated file.
+pub async fn forward_upload<S, E>(mut body: S, mut tx: UploadSender, limit: u64) -> Result<u64, UploadError>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: std::fmt::Display,
+{
+    let mut total = 0u64;
+    while let Some(frame) = body.next().await {
+        let err = match frame {
+            Ok(frame) => {
+                total += frame.len() as u64;
+                if total > limit {
+                    UploadError::TooLarge
+                } else {
+                    for start in (0..frame.len()).step_by(UPLOAD_CHUNK) {
+                        let chunk = frame.slice(start..frame.len().min(start + UPLOAD_CHUNK));
+                        if tx.send(Ok(chunk)).await.is_err() {
+                            // The node hung up; IpfsClient::add_stream reports why
+                            return Ok(total);
+                        }
+                    }
+                    continue;
+                }
+            }
+            Err(e) => UploadError::BadBody(e.to_string()),
+        };
+        abort_upload(tx, &err).await;
+        return Err(err);
+    }
+    Ok(total)
+}
+
+/// Push `err` into `tx` so the upload to the node fails instead of finishing
+/// (and pinning) whatever was sent so far
+pub async fn abort_upload(mut tx: UploadSender, err: &UploadError) {
+    let _ = tx.send(Err(std::io::Error::other(err.to_string()))).await;
+}
+
+/// Whether `cid` looks like a CID (v0 base58 "Qm..." or v1 multibase base32
+/// "b..."), so only plain content ids get passed on to the node's API
+pub fn is_valid_cid(cid: &str) -> bool {
+    let v0 = cid.len() == 46 && cid.starts_with("Qm") && cid.bytes().all(|b| b.is_ascii_alphanumeric() && !b"0OIl".contains(&b));
+    let v1 = (10..=128).contains(&cid.len()) && cid.starts_with('b') && cid.bytes().all(|b| matches!(b, b'a'..=b'z' | b'2'..=b'7'));
+    v0 || v1
+}
+
+/// A fake IPFS daemon on a local port, for tests here and of the routes that
+/// proxy to it
+#[cfg(test)]
+pub(crate) mod mock {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    /// What the fake daemon does with each request
+    #[derive(Clone)]
+    pub(crate) enum Reply {
+        /// Send this raw HTTP response
+        Respond(Vec<u8>),
+        /// Close the connection without answering
+        Close,
+        /// Read everything (body included) and never answer
+        Hang,
+    }
+
+    pub(crate) fn client(addr: std::net::SocketAddr) -> IpfsClient {
+        IpfsClient::new(IpfsConfig {
+            api_url: format!("http://{}", addr),
+            timeout: Duration::from_millis(500),
+            retries: 3,
+            backoff: Duration::from_millis(20),
+            ..IpfsConfig::default()
+        })
+        .unwrap()
+    }
+
+    /// Serve `reply` on `listener`, counting connections
+    pub(crate) fn serve(listener: TcpListener, reply: Reply) -> Arc<AtomicUsize> {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let count = connections.clone();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                count.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(answer(socket, reply.clone()));
+            }
+        });
+        connections
+    }
+
+    async fn answer(mut socket: TcpStream, reply: Reply) {
+        let mut request = Vec::new();
+        let mut buf = [0u8; 4096];
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+            match socket.read(&mut buf).await {
+                Ok(0) | Err(_) => return,
+                Ok(n) => request.extend_from_slice(&buf[..n]),
+            }
+        }
+        match reply {
+            Reply::Respond(response) => {
+                let _ = socket.write_all(&response).await;
+                let _ = socket.shutdown().await;
+            }
+            Reply::Close => {}
+            Reply::Hang => while !matches!(socket.read(&mut buf).await, Ok(0) | Err(_)) {},
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use tokio::net::TcpListener;
+
+    use super::mock::{client, serve, Reply};
+    use super::*;
+
+    const CID: &str = "QmYwAPJzv5CZsnA625s3Xf2nemtYgPpHdWEz79ojWnPbdG";
+
+    #[tokio::test]
+    async fn retries_while_the_connection_is_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let ipfs = client(addr);
+
+        // Nothing listening: every attempt is refused
+        let started = std::time::Instant::now();
+        assert!(matches!(ipfs.cat(CID).await, Err(IpfsError::Unreachable(_))));
+        // 20 + 40 + 80 ms of backoff between the four attempts
+        assert!(started.elapsed() >= Duration::from_millis(140));
+
+        // The daemon comes up during the backoff
+        let respond = Reply::Respond("HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello".into());
+        let late_start = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            serve(TcpListener::bind(addr).await.unwrap(), respond)
+        });
+        let res = ipfs.cat(CID).await.unwrap();
+        assert_eq!(res.text().await.unwrap(), "hello");
+        assert_eq!(late_start.await.unwrap().load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn error_statuses_are_not_retried() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let ipfs = client(listener.local_addr().unwrap());
+        let body = r#"{"Message":"boom","Code":0,"Type":"error"}"#;
+        let response = format!("HTTP/1.1 500 Internal Server Error\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body);
+        let connections = serve(listener, Reply::Respond(response.into()));
+
+        match ipfs.cat(CID).await {
+            Err(IpfsError::BadResponse(e)) => assert!(e.contains("500") && e.contains("boom"), "{}", e),
+            other => panic!("expected BadResponse, got {:?}", other.map(|_| ())),
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn dropped_connections_are_not_retried() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let ipfs = client(listener.local_addr().unwrap());
+        let connections = serve(listener, Reply::Close);
+
+        assert!(matches!(ipfs.cat(CID).await, Err(IpfsError::BadResponse(_))));
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn uploads_are_not_retried_once_the_body_was_taken() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let ipfs = client(listener.local_addr().unwrap());
+        let connections = serve(listener, Reply::Hang);
+
+        let (mut tx, rx) = mpsc::channel(UPLOAD_QUEUE);
+        tx.send(Ok(Bytes::from_static(b"attachment"))).await.unwrap();
+        drop(tx);
+        // The daemon takes the body and then times out, which alone would be retried
+        match ipfs.add_stream(rx).await {
+            Err(IpfsError::Unreachable(e)) => assert!(e.contains("after its body was sent"), "{}", e),
+            other => panic!("expected Unreachable, got {:?}", other),
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    /// Everything `forward_upload` pushed into the channel, in order
+    async fn forwarded(frames: Vec<Result<Bytes, String>>, limit: u64) -> (Result<u64, UploadError>, Vec<Result<Bytes, String>>) {
+        let (tx, rx) = mpsc::channel(UPLOAD_QUEUE);
+        let (res, sent) = tokio::join!(
+            forward_upload(futures::stream::iter(frames), tx, limit),
+            rx.map(|chunk| chunk.map_err(|e| e.to_string())).collect::<Vec<_>>(),
+        );
+        (res, sent)
+    }
+
+    #[tokio::test]
+    async fn splits_frames_into_chunks() {
+        let frame = Bytes::from(vec![7u8; 2 * UPLOAD_CHUNK + 10]);
+        let (res, sent) = forwarded(vec![Ok(frame), Ok(Bytes::from_static(b"tail"))], u64::MAX).await;
+        assert_eq!(res.unwrap(), 2 * UPLOAD_CHUNK as u64 + 14);
+        let lens: Vec<usize> = sent.iter().map(|chunk| chunk.as_ref().unwrap().len()).collect();
+        assert_eq!(lens, [UPLOAD_CHUNK, UPLOAD_CHUNK, 10, 4]);
+    }
+
+    #[tokio::test]
+    async fn stops_at_the_limit_and_aborts_the_upload() {
+        let frames = vec![Ok(Bytes::from_static(b"12345")), Ok(Bytes::from_static(b"678"))];
+        let (res, sent) = forwarded(frames, 7).await;
+        assert!(matches!(res, Err(UploadError::TooLarge)));
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0].as_deref().unwrap(), b"12345");
+        assert_eq!(sent[1].as_ref().unwrap_err(), "upload too large");
+
+        // Exactly at the limit is fine
+        let (res, sent) = forwarded(vec![Ok(Bytes::from_static(b"1234567"))], 7).await;
+        assert_eq!(res.unwrap(), 7);
+        assert!(sent.iter().all(Result::is_ok));
+    }
+
+    #[tokio::test]
+    async fn body_errors_abort_the_upload() {
+        let frames = vec![Ok(Bytes::from_static(b"start")), Err("connection reset".to_string())];
+        let (res, sent) = forwarded(frames, u64::MAX).await;
+        assert!(matches!(res, Err(UploadError::BadBody(ref e)) if e == "connection reset"));
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[1].as_ref().unwrap_err(), "bad upload body: connection reset");
+    }
+
+    #[test]
+    fn accepts_only_plain_cids() {
+        for cid in [
+            "QmYwAPJzv5CZsnA625s3Xf2nemtYgPpHdWEz79ojWnPbdG",
+            "bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi",
+            "bafkreifzjut3te2nhyekklss27nh3k72ysco7y32koao5eei66wof36n5e",
+        ] {
+            assert!(is_valid_cid(cid), "{}", cid);
+        }
+        for cid in [
+            "",
+            // v0: wrong length, base58-excluded characters, wrong prefix
+            "QmYwAPJzv5CZsnA625s3Xf2nemtYgPpHdWEz79ojWnPbd",
+            "QmYwAPJzv5CZsnA625s3Xf2nemtYgPpHdWEz79ojWnPb0G",
+            "QmYwAPJzv5CZsnA625s3Xf2nemtYgPpHdWEz79ojWnPbIl",
+            "QnYwAPJzv5CZsnA625s3Xf2nemtYgPpHdWEz79ojWnPbdG",
+            // v1: upper case, digits outside base32, too short, too long, not "b"
+            "BAFYBEIGDYRZT5SFP7UDM7HU76UH7Y26NF3EFUYLQABF3OCLGTQY55FBZDI",
+            "bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzd1",
+            "bafybei",
+            &format!("b{}", "a".repeat(128)),
+            "zdj7WhuEjrB52m1BisYCtmjH1hSKa7yZ3jEZ9JcXaFRD51wVz",
+            // Anything that would change the API call
+            "QmYwAPJzv5CZsnA625s3Xf2nemtYgPpHdWEz79ojWnPbdG&arg=x",
+            "bafy/../../api",
+        ] {
+            assert!(!is_valid_cid(cid), "{}", cid);
+        }
     }
 }
@@ -0,0 +1,277 @@
+// src/keydir.rs
+// Public key directory: every Kyber public key a user has published, newest first.
+// Devices that speak the hybrid KEM publish an X25519 key alongside their Kyber key,
+// and devices that sign their envelopes publish their ML-DSA identity key. Each key
+// records which KEM it's for (`crypto::KemVersion`: Kyber1024, or ML-KEM-768/1024).
+// Each of a user's devices publishes its own key; a device's "current" key is the
+// most recently published one for that device that isn't revoked. Older keys stay
+// listed so clients can still decrypt messages sent to them.
+use rusqlite::{params, OptionalExtension, Row};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use crate::db::{self, Db};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct KeyRecord {
+    pub key_id: String,
+    pub username: String,
+    /// Device holding the secret half
+    pub device_id: String,
+    /// base64 of the raw public key
+    pub pubkey: String,
+    /// KEM the public key is for (`crypto::KemVersion` as a number)
+    pub kem: u8,
+    /// base64 of the device's X25519 key, if it supports the hybrid KEM
+    pub x25519_pubkey: Option<String>,
+    /// base64 of the device's ML-DSA-65 identity key, which verifies its envelopes
+    pub sig_pubkey: Option<String>,
+    /// SHA-256 (hex) of the raw public key and the X25519 and signature keys
+    /// (see `fingerprint`)
+    pub fingerprint: String,
+    pub created_at: i64,
+    pub published_at: i64,
+    pub revoked_at: Option<i64>,
+}
+
+#[derive(Debug)]
+pub enum PublishError {
+    /// The key was revoked earlier and can't be made current again
+    Revoked,
+    /// The same key was published before for a different KEM
+    KemMismatch,
+    Db(rusqlite::Error),
+}
+
+impl std::fmt::Display for PublishError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PublishError::Revoked => write!(f, "key has been revoked"),
+            PublishError::KemMismatch => write!(f, "key was published for a different kem"),
+            PublishError::Db(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for PublishError {
+    fn from(e: rusqlite::Error) -> Self {
+        PublishError::Db(e)
+    }
+}
+
+const KEY_COLUMNS: &str = "key_id, username, pubkey, fingerprint, created_at, published_at, revoked_at, device_id, x25519_pubkey, sig_pubkey, kem";
+
+fn from_row(row: &Row) -> rusqlite::Result<KeyRecord> {
+    Ok(KeyRecord {
+        key_id: row.get(0)?,
+        username: row.get(1)?,
+        pubkey: row.get(2)?,
+        fingerprint: row.get(3)?,
+        created_at: row.get(4)?,
+        published_at: row.get(5)?,
+        revoked_at: row.get(6)?,
+        device_id: row.get(7)?,
+        x25519_pubkey: row.get(8)?,
+        sig_pubkey: row.get(9)?,
+        kem: row.get(10)?,
+    })
+}
+
+/// SHA-256 hex of the raw key bytes, followed by the X25519 and signature keys
+/// (as published) when the device has them. Those keys are covered so that
+/// swapping one gives a new key id and fingerprint, and with it a `key_changed`.
+/// Raw keys have a fixed length per KEM, so the tags can't be confused with key
+/// bytes; a bare key keeps the fingerprint it always had.
+pub fn fingerprint(raw_key: &[u8], x25519_b64: Option<&str>, sig_b64: Option<&str>) -> String {
+    let mut hash = Sha256::new();
+    hash.update(raw_key);
+    if let Some(x25519) = x25519_b64 {
+        hash.update(b"x25519:");
+        hash.update(x25519.as_bytes());
+    }
+    if let Some(sig) = sig_b64 {
+        hash.update(b"ml-dsa-65:");
+        hash.update(sig.as_bytes());
+    }
+    format!("{:x}", hash.finalize())
+}
+
+/// Short identifier clients put in envelopes to say which key they encrypted to
+fn key_id_for(fingerprint: &str) -> String {
+    fingerprint[..16].to_string()
+}
+
+/// A device key being published. Optional keys are base64.
+pub struct NewKey<'a> {
+    pub pubkey_b64: &'a str,
+    pub raw_key: &'a [u8],
+    pub kem: u8,
+    pub x25519_b64: Option<&'a str>,
+    pub sig_b64: Option<&'a str>,
+}
+
+/// Publish a key for one of `username`'s devices and make it that device's current
+/// key. Re-publishing a key the user already has just makes it current again; a
+/// device that gains an X25519 or signature key publishes a new key (same KEM
+/// key, new key id), so those keys never change under an existing key id.
+pub fn publish_key(db: &Db, username: &str, device_id: &str, key: &NewKey) -> Result<KeyRecord, PublishError> {
+    let NewKey { pubkey_b64, raw_key, kem, x25519_b64, sig_b64 } = *key;
+    let fp = fingerprint(raw_key, x25519_b64, sig_b64);
+    let key_id = key_id_for(&fp);
+    let now = db::unix_now();
+    let conn = db.lock().unwrap();
+    let existing: Option<(Option<i64>, u8)> = conn
+        .query_row(
+            "SELECT revoked_at, kem FROM pubkeys WHERE username = ?1 AND key_id = ?2",
+            params![username, key_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?;
+    match existing {
+        Some((Some(_), _)) => return Err(PublishError::Revoked),
+        Some((None, existing_kem)) if existing_kem != kem => return Err(PublishError::KemMismatch),
+        Some((None, _)) => {
+            conn.execute(
+                "UPDATE pubkeys SET published_at = ?1, device_id = ?2 WHERE username = ?3 AND key_id = ?4",
+                params![now, device_id, username, key_id],
+            )?;
+        }
+        None => {
+            conn.execute(
+                "INSERT INTO pubkeys (key_id, username, pubkey, fingerprint, created_at, published_at, device_id,
+                 x25519_pubkey, sig_pubkey, kem) VALUES (?1, ?2, ?3, ?4, ?5, ?5, ?6, ?7, ?8, ?9)",
+                params![key_id, username, pubkey_b64, fp, now, device_id, x25519_b64, sig_b64, kem],
+            )?;
+        }
+    }
+    let sql = format!("SELECT {} FROM pubkeys WHERE username = ?1 AND key_id = ?2", KEY_COLUMNS);
+    Ok(conn.query_row(&sql, params![username, key_id], from_row)?)
+}
+
+/// One key by id (revoked or not)
+pub fn key(db: &Db, username: &str, key_id: &str) -> rusqlite::Result<Option<KeyRecord>> {
+    let conn = db.lock().unwrap();
+    let sql = format!("SELECT {} FROM pubkeys WHERE username = ?1 AND key_id = ?2", KEY_COLUMNS);
+    conn.query_row(&sql, params![username, key_id], from_row).optional()
+}
+
+/// Current (newest non-revoked) key of one of the user's devices
+pub fn current_key(db: &Db, username: &str, device_id: &str) -> rusqlite::Result<Option<KeyRecord>> {
+    let conn = db.lock().unwrap();
+    let sql = format!(
+        "SELECT {} FROM pubkeys WHERE username = ?1 AND device_id = ?2 AND revoked_at IS NULL
+         ORDER BY published_at DESC, rowid DESC LIMIT 1",
+        KEY_COLUMNS
+    );
+    conn.query_row(&sql, params![username, device_id], from_row).optional()
+}
+
+/// Current key of every device that has one, grouped by user. Pass a username to
+/// list just that user's devices.
+fn current_device_keys(db: &Db, username: Option<&str>) -> rusqlite::Result<Vec<KeyRecord>> {
+    let conn = db.lock().unwrap();
+    let sql = format!(
+        "SELECT {} FROM pubkeys p WHERE revoked_at IS NULL AND (?1 IS NULL OR username = ?1) AND rowid = (
+            SELECT rowid FROM pubkeys q
+            WHERE q.username = p.username AND q.device_id = p.device_id AND q.revoked_at IS NULL
+            ORDER BY published_at DESC, rowid DESC LIMIT 1
+         ) ORDER BY username, published_at DESC",
+        KEY_COLUMNS
+    );
+    let mut stmt = conn.prepare(&sql)?;
+    let rows = stmt.query_map(params![username], from_row)?;
+    rows.collect()
+}
+
+/// Current key of every device of every user
+pub fn current_keys(db: &Db) -> rusqlite::Result<Vec<KeyRecord>> {
+    current_device_keys(db, None)
+}
+
+/// Current key of each of one user's devices, most recently published first
+pub fn device_keys(db: &Db, username: &str) -> rusqlite::Result<Vec<KeyRecord>> {
+    current_device_keys(db, Some(username))
+}
+
+/// Every key the user ever published (including revoked), newest first
+pub fn key_history(db: &Db, username: &str) -> rusqlite::Result<Vec<KeyRecord>> {
+    let conn = db.lock().unwrap();
+    let sql = format!(
+        "SELECT {} FROM pubkeys WHERE username = ?1 ORDER BY created_at DESC, rowid DESC",
+        KEY_COLUMNS
+    );
+    let mut stmt = conn.prepare(&sql)?;
+    let rows = stmt.query_map(params![username], from_row)?;
+    rows.collect()
+}
+
+/// Revoke one of the user's keys. Returns false if no such live key.
+pub fn revoke_key(db: &Db, username: &str, key_id: &str) -> rusqlite::Result<bool> {
+    let conn = db.lock().unwrap();
+    let changed = conn.execute(
+        "UPDATE pubkeys SET revoked_at = ?1 WHERE username = ?2 AND key_id = ?3 AND revoked_at IS NULL",
+        params![db::unix_now(), username, key_id],
+    )?;
+    Ok(changed > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn publish(db: &Db, device_id: &str, raw: &[u8], kem: u8) -> Result<KeyRecord, PublishError> {
+        publish_key(db, "alice", device_id, &NewKey { pubkey_b64: "AAAA", raw_key: raw, kem, x25519_b64: None, sig_b64: None })
+    }
+
+    #[test]
+    fn republishing_a_key_for_another_kem_conflicts() {
+        let db = db::open(":memory:").unwrap();
+        let first = publish(&db, "laptop", b"key one", 1).unwrap();
+        assert_eq!(first.key_id, key_id_for(&fingerprint(b"key one", None, None)));
+        // Same key, same KEM: just current again
+        assert_eq!(publish(&db, "laptop", b"key one", 1).unwrap().key_id, first.key_id);
+        assert!(matches!(publish(&db, "laptop", b"key one", 2), Err(PublishError::KemMismatch)));
+        assert_eq!(key_history(&db, "alice").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn revoked_keys_stop_being_current_and_cant_come_back() {
+        let db = db::open(":memory:").unwrap();
+        let old = publish(&db, "laptop", b"old key", 1).unwrap();
+        let new = publish(&db, "laptop", b"new key", 1).unwrap();
+        let phone = publish(&db, "phone", b"phone key", 1).unwrap();
+        assert_eq!(current_key(&db, "alice", "laptop").unwrap().unwrap().key_id, new.key_id);
+
+        assert!(revoke_key(&db, "alice", &new.key_id).unwrap());
+        assert!(!revoke_key(&db, "alice", &new.key_id).unwrap());
+        assert!(!revoke_key(&db, "bob", &old.key_id).unwrap());
+        // The device falls back to its older live key; revoked keys stay listed
+        assert_eq!(current_key(&db, "alice", "laptop").unwrap().unwrap().key_id, old.key_id);
+        assert!(key(&db, "alice", &new.key_id).unwrap().unwrap().revoked_at.is_some());
+        assert!(matches!(publish(&db, "laptop", b"new key", 1), Err(PublishError::Revoked)));
+
+        assert!(revoke_key(&db, "alice", &old.key_id).unwrap());
+        assert!(current_key(&db, "alice", "laptop").unwrap().is_none());
+        let devices: Vec<String> = device_keys(&db, "alice").unwrap().into_iter().map(|k| k.key_id).collect();
+        assert_eq!(devices, vec![phone.key_id]);
+    }
+
+    #[test]
+    fn side_keys_cannot_change_under_an_existing_key_id() {
+        let db = db::open(":memory:").unwrap();
+        let with = |x25519, sig| NewKey { pubkey_b64: "AAAA", raw_key: b"kyber key", kem: 1, x25519_b64: x25519, sig_b64: sig };
+        let bare = publish_key(&db, "alice", "laptop", &with(None, None)).unwrap();
+        let hybrid = publish_key(&db, "alice", "laptop", &with(Some("x25519 one"), Some("sig one"))).unwrap();
+        let swapped = publish_key(&db, "alice", "laptop", &with(Some("x25519 one"), Some("sig two"))).unwrap();
+        let fingerprints = [&bare.fingerprint, &hybrid.fingerprint, &swapped.fingerprint];
+        assert!(fingerprints[0] != fingerprints[1] && fingerprints[1] != fingerprints[2] && fingerprints[0] != fingerprints[2]);
+        assert_eq!(bare.fingerprint, fingerprint(b"kyber key", None, None));
+
+        // Each combination is its own key; the earlier ones keep what they were published with
+        assert_eq!(key(&db, "alice", &hybrid.key_id).unwrap().unwrap().sig_pubkey.as_deref(), Some("sig one"));
+        let current = current_key(&db, "alice", "laptop").unwrap().unwrap();
+        assert_eq!((current.key_id, current.sig_pubkey.as_deref()), (swapped.key_id, Some("sig two")));
+        // Leaving the side keys out doesn't strip them from a key either
+        assert_eq!(publish_key(&db, "alice", "laptop", &with(None, None)).unwrap().key_id, bare.key_id);
+        assert_eq!(key_history(&db, "alice").unwrap().len(), 3);
+    }
+}
@@ -0,0 +1,5 @@
+// src/lib.rs
+// Rust side of the E2EE scheme, as a library: the reference implementation of
+// what clients do (see examples/crypto_demo.rs). The relay itself never encrypts
+// or decrypts; the server binary only uses the key validation and KEM ids.
+pub mod crypto;
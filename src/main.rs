@@ -5,9 +5,15 @@ mod auth;
 mod ws;
 mod routes;
 mod ipfs;
-pub mod crypto;
+mod db;
+mod keydir;
+mod signing;
+mod queue;
+mod protocol;
+mod groups;
+use noid_messenger::crypto;
 
-use axum::{Router, routing::{get, post}};
+use axum::{Router, extract::DefaultBodyLimit, routing::{delete, get, post}};
 use axum::http::StatusCode;
 use tower_http::trace::TraceLayer;
 use tower_http::compression::CompressionLayer;
@@ -20,7 +26,12 @@ use crate::state::AppState;
 async fn main() {
     tracing_subscriber::fmt::init();
 
-    let state = AppState::new();
+    let db = db::open(&db::db_path()).expect("failed to open database");
+    let server_key = signing::load_or_create(&db).expect("failed to load server signing key");
+    let ipfs = ipfs::IpfsClient::new(ipfs::IpfsConfig::from_env()).expect("invalid IPFS client configuration");
+    let state = AppState::new(db, server_key, ipfs);
+    auth::spawn_token_sweeper(state.db.clone());
+    queue::spawn_queue_sweeper(state.db.clone());
 
     let app = Router::new()
         // Avoid console 404 noise for favicon
@@ -36,11 +47,25 @@ async fn main() {
         )
         .route("/", get(routes::index))
         .route("/login", post(routes::login_handler))
-        .route("/ipfs/add", post(routes::ipfs_add))
+        .route("/register", post(routes::register_handler))
+        .route("/logout", post(routes::logout_handler))
+        .route("/refresh", post(routes::refresh_handler))
+        // Uploads are capped by IpfsClient::max_upload_bytes while they stream
+        .route("/ipfs/add", post(routes::ipfs_add).layer(DefaultBodyLimit::disable()))
+        .route("/ipfs/get/:cid", get(routes::ipfs_get))
         .route("/chat", get(routes::chat))
         .route("/ws", get(ws::ws_handler))
         .route("/pubkey", post(routes::post_pubkey))
         .route("/pubkeys", get(routes::get_pubkeys))
+        .route("/pubkeys/:user", get(routes::get_user_pubkeys))
+        .route("/pubkeys/:user/:key_id", delete(routes::revoke_pubkey))
+        .route("/server-key", get(routes::get_server_key))
+        .route("/groups", post(routes::create_group).get(routes::list_groups))
+        .route("/groups/:id", get(routes::get_group))
+        .route("/groups/:id/members", get(routes::get_group_members))
+        .route("/groups/:id/invite", post(routes::invite_to_group))
+        .route("/groups/:id/leave", post(routes::leave_group))
+        .route("/groups/:id/kick", post(routes::kick_from_group))
         .route_service(
             "/static/*file",
             get_service(ServeDir::new(r"C:/Users/Hurtf/OneDrive/Desktop/noid-messenger/static"))
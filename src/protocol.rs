@@ -0,0 +1,514 @@
+// src/protocol.rs
+//! Wire protocol spoken over `/ws`. Every frame is a JSON object tagged by `type`.
+//!
+//! Handshake: the first client frame must be `hello`:
+//!
+//! ```json
+//! {"type":"hello","username":"alice","token":"<session token>","versions":[2],"device_id":"..."}
+//! ```
+//!
+//! `versions` lists the protocol versions the client speaks (omitted = `[1]`).
+//! The server picks the highest one it also supports and answers
+//! `{"type":"welcome","version":2,"username":"alice","device_id":"..."}`, or an
+//! `error` frame with code `unsupported_version` and closes.
+//!
+//! A user may be connected from several devices at once, and from several
+//! sockets (tabs) per device. `device_id` (1-64 chars) names the device that
+//! holds a Kyber secret key and must match the `device_id` its public key was
+//! published under; without one the server assigns a fresh id and returns it in
+//! `welcome`. Frames for a user are delivered to all of their sockets.
+//!
+//! Version 2 `ciphertext` envelopes are encrypted once and carry one key slot per
+//! recipient device:
+//!
+//! ```json
+//! {"type":"ciphertext","to":"bob","mid":"...","nonce":"<12 bytes>","ciphertext":"...",
+//!  "slots":[{"device_id":"...","key_id":"...","kyber_ct":"<1568 bytes>","wrapped_key":"<48 bytes>"}]}
+//! ```
+//!
+//! The message is AES-256-GCM under a random content key. Each slot encapsulates
+//! to that device's Kyber key and carries the content key AES-256-GCM-encrypted
+//! under the slot's shared secret with the envelope nonce (every shared secret is
+//! fresh, so it's only ever used for this one encryption). The relay delivers each
+//! recipient device the envelope with only its own slot, and queues slots for
+//! devices that are offline.
+//!
+//! A slot's `kem` says how its shared secret was made: `1` (the default) is
+//! Kyber1024 alone; `2` is the X25519 + Kyber1024 hybrid, where the slot also
+//! carries the sender's ephemeral X25519 key in `x25519_epk` (32 bytes) and the
+//! secret is HKDF-SHA256 over both component secrets (see `crypto::hybrid`).
+//! Senders only use `2` for devices that published an X25519 key, so clients
+//! without hybrid support keep receiving Kyber-only slots. `3` and `4` are
+//! ML-KEM-768 and ML-KEM-1024 (FIPS 203), for devices whose directory key has
+//! that `kem`; their `kyber_ct` is the ML-KEM ciphertext (1088 and 1568 bytes).
+//!
+//! `group_ciphertext` is the same envelope addressed to a group (`group` instead
+//! of `to`); every slot names the member it's for in `to`. The sender must be a
+//! member, and slots for users who aren't current members are dropped. Membership
+//! changes are pushed to the members as `group_changed`.
+//!
+//! Client -> server after the handshake: `ciphertext`, `group_ciphertext`,
+//! `plaintext`, `read`.
+//! Server -> client: `welcome`, `system`, `presence`, `ciphertext`,
+//! `group_ciphertext`, `plaintext`, `delivered`, `read`, `failed`, `key_changed`,
+//! `group_changed`, `error`.
+//!
+//! The relay stamps `from` on `ciphertext` and `plaintext` with the user the
+//! socket authenticated as; clients may omit it, and a frame whose `from` names
+//! anyone else is rejected with `sender_mismatch`. Browser envelopes don't
+//! carry identity signatures yet, so for now this stamp is all that names their
+//! sender.
+//!
+//! Frames are parsed strictly: unknown `type`s, unknown fields, missing fields and
+//! malformed values are answered with an `error` frame
+//! (`{"type":"error","code":"invalid_frame","msg":"..."}`) and the frame is dropped.
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use crate::crypto::KemVersion;
+
+/// Highest protocol version this server speaks
+pub const PROTOCOL_VERSION: u32 = 2;
+/// Oldest protocol version this server still accepts (v1 envelopes had a single
+/// kyber_ct and can't reach multiple devices)
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+
+/// AES-GCM nonce length in bytes
+const NONCE_LEN: usize = 12;
+/// X25519 public key length in bytes
+const X25519_KEY_LEN: usize = 32;
+/// AES-256 content key plus GCM tag
+const WRAPPED_KEY_LEN: usize = 32 + 16;
+/// Most recipient devices one envelope may address
+const MAX_SLOTS: usize = 32;
+/// Most member devices one group envelope may address
+const MAX_GROUP_SLOTS: usize = 1024;
+/// Longest message id we accept
+const MAX_MID_LEN: usize = 64;
+/// Longest device id we accept
+pub const MAX_DEVICE_ID_LEN: usize = 64;
+
+fn default_versions() -> Vec<u32> {
+    vec![1]
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Hello {
+    pub username: String,
+    pub token: String,
+    #[serde(default = "default_versions")]
+    pub versions: Vec<u32>,
+    #[serde(default)]
+    pub device_id: Option<String>,
+}
+
+/// End-to-end encrypted message. The server only routes it; `nonce`,
+/// `ciphertext` and the slots' key material are base64 and opaque to the relay.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Ciphertext {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    pub to: String,
+    /// Message id (client-chosen; the server fills one in if missing)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mid: Option<String>,
+    pub nonce: String,
+    pub ciphertext: String,
+    /// One per recipient device
+    pub slots: Vec<KeySlot>,
+}
+
+/// End-to-end encrypted message to a group; like `Ciphertext` but every slot
+/// names the member it's for
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GroupCiphertext {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    pub group: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mid: Option<String>,
+    pub nonce: String,
+    pub ciphertext: String,
+    pub slots: Vec<KeySlot>,
+}
+
+/// The content key of a `Ciphertext`, encapsulated to one recipient device
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeySlot {
+    /// Member this slot is for (group envelopes only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+    pub device_id: String,
+    /// Directory key id of the device key this was encapsulated to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_id: Option<String>,
+    /// KEM the slot was encapsulated with (`crypto::KemVersion`)
+    #[serde(default = "default_kem")]
+    pub kem: u8,
+    /// Sender's ephemeral X25519 key (hybrid slots only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub x25519_epk: Option<String>,
+    pub kyber_ct: String,
+    pub wrapped_key: String,
+}
+
+fn default_kem() -> u8 {
+    KemVersion::Kyber1024 as u8
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Plaintext {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    #[serde(default)]
+    pub to: Option<String>,
+    pub data: String,
+}
+
+/// Read ack: the client read message `mid` sent by `to`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReadAck {
+    pub mid: String,
+    pub to: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientFrame {
+    Hello(Hello),
+    Ciphertext(Ciphertext),
+    GroupCiphertext(GroupCiphertext),
+    Plaintext(Plaintext),
+    Read(ReadAck),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerFrame {
+    Welcome { version: u32, username: String, device_id: String },
+    System { msg: String },
+    Presence { online: Vec<String> },
+    Ciphertext(Ciphertext),
+    GroupCiphertext(GroupCiphertext),
+    Plaintext(Plaintext),
+    Delivered { mid: String, to: String, ts: i64 },
+    Read { mid: String, from: String, ts: i64 },
+    Failed {
+        mid: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        to: Option<String>,
+        reason: String,
+        ts: i64,
+    },
+    /// Signed by the server key; see ws::broadcast_key_changed
+    KeyChanged {
+        username: String,
+        device_id: String,
+        key_id: Option<String>,
+        pubkey: Option<String>,
+        /// KEM of the new key (`crypto::KemVersion`)
+        kem: Option<u8>,
+        old_fingerprint: Option<String>,
+        new_fingerprint: Option<String>,
+        ts: i64,
+        sig: String,
+    },
+    /// Group created or membership changed; `event` is created / joined / left /
+    /// kicked, `username` who it happened to and `by` who did it
+    GroupChanged {
+        group_id: String,
+        name: String,
+        event: String,
+        username: String,
+        by: String,
+        owner: String,
+        members: Vec<String>,
+        ts: i64,
+    },
+    Error { code: ErrorCode, msg: String },
+}
+
+impl ServerFrame {
+    pub fn error(code: ErrorCode, msg: impl Into<String>) -> Self {
+        ServerFrame::Error { code, msg: msg.into() }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("server frames always serialize")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// First frame wasn't a valid hello
+    HelloRequired,
+    /// No protocol version in common
+    UnsupportedVersion,
+    /// Token belongs to a different user
+    InvalidToken,
+    /// Token unknown or expired
+    UnknownToken,
+    /// Not JSON, unknown type, or wrong/missing fields
+    InvalidFrame,
+    /// Well-formed frame with a bad value (e.g. nonce of the wrong length)
+    InvalidField,
+    /// hello sent again after the handshake
+    UnexpectedHello,
+    /// `from` names someone other than the authenticated user
+    SenderMismatch,
+    /// Group doesn't exist or the sender isn't a member
+    NotGroupMember,
+    /// Binary frames aren't part of the protocol
+    UnsupportedFrame,
+}
+
+/// A frame that failed validation, ready to send back as an `error` frame
+#[derive(Debug)]
+pub struct ProtocolError {
+    pub code: ErrorCode,
+    pub msg: String,
+}
+
+impl ProtocolError {
+    fn field(msg: impl Into<String>) -> Self {
+        ProtocolError { code: ErrorCode::InvalidField, msg: msg.into() }
+    }
+
+    pub fn to_frame(&self) -> ServerFrame {
+        ServerFrame::error(self.code, self.msg.clone())
+    }
+}
+
+/// Parse one text frame from a client
+pub fn parse_client_frame(text: &str) -> Result<ClientFrame, ProtocolError> {
+    serde_json::from_str(text).map_err(|e| ProtocolError { code: ErrorCode::InvalidFrame, msg: e.to_string() })
+}
+
+/// Highest version both sides speak, if any
+pub fn negotiate_version(client_versions: &[u32]) -> Option<u32> {
+    client_versions
+        .iter()
+        .copied()
+        .filter(|v| (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(v))
+        .max()
+}
+
+fn decoded_len(field: &str, value: &str) -> Result<usize, ProtocolError> {
+    base64::engine::general_purpose::STANDARD
+        .decode(value)
+        .map(|b| b.len())
+        .map_err(|_| ProtocolError::field(format!("{} is not valid base64", field)))
+}
+
+fn validate_mid(mid: Option<&str>) -> Result<(), ProtocolError> {
+    match mid {
+        Some(m) if m.is_empty() || m.len() > MAX_MID_LEN => {
+            Err(ProtocolError::field(format!("mid must be 1-{} characters", MAX_MID_LEN)))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Stamp the authenticated sender onto a frame's `from`, rejecting frames that
+/// claim to be from somebody else
+fn stamp_sender(from: &mut Option<String>, uname: &str) -> Result<(), ProtocolError> {
+    if let Some(claimed) = from.as_deref() {
+        if crate::auth::normalize_username(claimed) != uname {
+            return Err(ProtocolError {
+                code: ErrorCode::SenderMismatch,
+                msg: format!("from must be '{}'", uname),
+            });
+        }
+    }
+    *from = Some(uname.to_string());
+    Ok(())
+}
+
+/// Device ids are 1-MAX_DEVICE_ID_LEN printable characters
+pub fn valid_device_id(device_id: &str) -> bool {
+    !device_id.is_empty() && device_id.len() <= MAX_DEVICE_ID_LEN && !device_id.chars().any(char::is_control)
+}
+
+fn validate_device_id(device_id: &str) -> Result<(), ProtocolError> {
+    if valid_device_id(device_id) {
+        Ok(())
+    } else {
+        Err(ProtocolError::field(format!("device_id must be 1-{} printable characters", MAX_DEVICE_ID_LEN)))
+    }
+}
+
+impl Hello {
+    pub fn validate(&self) -> Result<(), ProtocolError> {
+        match self.device_id.as_deref() {
+            Some(d) => validate_device_id(d),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Ciphertext {
+    pub fn validate(&self) -> Result<(), ProtocolError> {
+        if self.to.trim().is_empty() {
+            return Err(ProtocolError::field("missing recipient"));
+        }
+        validate_mid(self.mid.as_deref())?;
+        if self.slots.is_empty() || self.slots.len() > MAX_SLOTS {
+            return Err(ProtocolError::field(format!("slots must have 1-{} entries", MAX_SLOTS)));
+        }
+        let mut devices = std::collections::HashSet::new();
+        for slot in &self.slots {
+            slot.validate()?;
+            if slot.to.is_some() {
+                return Err(ProtocolError::field("slot.to is only used in group envelopes"));
+            }
+            if !devices.insert(slot.device_id.as_str()) {
+                return Err(ProtocolError::field(format!("duplicate slot for device {}", slot.device_id)));
+            }
+        }
+        validate_body(&self.nonce, &self.ciphertext)
+    }
+
+    pub fn stamp_sender(&mut self, uname: &str) -> Result<(), ProtocolError> {
+        stamp_sender(&mut self.from, uname)
+    }
+}
+
+impl GroupCiphertext {
+    pub fn validate(&self) -> Result<(), ProtocolError> {
+        if self.group.trim().is_empty() {
+            return Err(ProtocolError::field("missing group"));
+        }
+        validate_mid(self.mid.as_deref())?;
+        if self.slots.is_empty() || self.slots.len() > MAX_GROUP_SLOTS {
+            return Err(ProtocolError::field(format!("slots must have 1-{} entries", MAX_GROUP_SLOTS)));
+        }
+        let mut devices = std::collections::HashSet::new();
+        for slot in &self.slots {
+            slot.validate()?;
+            let Some(to) = slot.to.as_deref() else {
+                return Err(ProtocolError::field("every group slot needs a to"));
+            };
+            if !devices.insert((to, slot.device_id.as_str())) {
+                return Err(ProtocolError::field(format!("duplicate slot for {}/{}", to, slot.device_id)));
+            }
+        }
+        validate_body(&self.nonce, &self.ciphertext)
+    }
+
+    pub fn stamp_sender(&mut self, uname: &str) -> Result<(), ProtocolError> {
+        stamp_sender(&mut self.from, uname)
+    }
+}
+
+fn validate_body(nonce: &str, ciphertext: &str) -> Result<(), ProtocolError> {
+    if decoded_len("nonce", nonce)? != NONCE_LEN {
+        return Err(ProtocolError::field(format!("nonce must be {} bytes", NONCE_LEN)));
+    }
+    if decoded_len("ciphertext", ciphertext)? == 0 {
+        return Err(ProtocolError::field("ciphertext is empty"));
+    }
+    Ok(())
+}
+
+impl KeySlot {
+    fn validate(&self) -> Result<(), ProtocolError> {
+        validate_device_id(&self.device_id)?;
+        let kem = KemVersion::from_u8(self.kem)
+            .ok_or_else(|| ProtocolError::field(format!("unsupported kem {}", self.kem)))?;
+        match (kem, self.x25519_epk.as_deref()) {
+            (KemVersion::X25519Kyber1024, Some(epk)) => {
+                if decoded_len("x25519_epk", epk)? != X25519_KEY_LEN {
+                    return Err(ProtocolError::field(format!("x25519_epk must be {} bytes", X25519_KEY_LEN)));
+                }
+            }
+            (KemVersion::X25519Kyber1024, None) => {
+                return Err(ProtocolError::field("kem 2 slots need an x25519_epk"));
+            }
+            (_, Some(_)) => return Err(ProtocolError::field("x25519_epk is only used with kem 2")),
+            (_, None) => {}
+        }
+        // Hybrid slots carry the X25519 half separately, so kyber_ct is just the Kyber part
+        let ct_len = match kem {
+            KemVersion::X25519Kyber1024 => KemVersion::Kyber1024.ciphertext_bytes(),
+            kem => kem.ciphertext_bytes(),
+        };
+        if decoded_len("kyber_ct", &self.kyber_ct)? != ct_len {
+            return Err(ProtocolError::field(format!("kyber_ct must be {} bytes for kem {}", ct_len, self.kem)));
+        }
+        if decoded_len("wrapped_key", &self.wrapped_key)? != WRAPPED_KEY_LEN {
+            return Err(ProtocolError::field(format!("wrapped_key must be {} bytes", WRAPPED_KEY_LEN)));
+        }
+        Ok(())
+    }
+}
+
+impl Plaintext {
+    pub fn validate(&self) -> Result<(), ProtocolError> {
+        match self.to.as_deref() {
+            Some(to) if !to.trim().is_empty() => Ok(()),
+            _ => Err(ProtocolError::field("missing recipient")),
+        }
+    }
+
+    pub fn stamp_sender(&mut self, uname: &str) -> Result<(), ProtocolError> {
+        stamp_sender(&mut self.from, uname)
+    }
+}
+
+impl ReadAck {
+    pub fn validate(&self) -> Result<(), ProtocolError> {
+        if self.to.trim().is_empty() {
+            return Err(ProtocolError::field("missing recipient"));
+        }
+        validate_mid(Some(&self.mid))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn code(text: &str) -> Option<ErrorCode> {
+        parse_client_frame(text).err().map(|e| e.code)
+    }
+
+    #[test]
+    fn unknown_fields_and_types_are_rejected() {
+        let slot = r#"{"device_id":"d","kyber_ct":"AA==","wrapped_key":"AA=="}"#;
+        assert_eq!(code(r#"{"type":"hello","username":"a","token":"t","versions":[2]}"#), None);
+        assert_eq!(code(r#"{"type":"hello","username":"a","token":"t","admin":true}"#), Some(ErrorCode::InvalidFrame));
+        assert_eq!(code(r#"{"type":"read","mid":"m","to":"a","extra":1}"#), Some(ErrorCode::InvalidFrame));
+        assert_eq!(
+            code(&format!(r#"{{"type":"ciphertext","to":"b","nonce":"","ciphertext":"","slots":[{}]}}"#, slot)),
+            None
+        );
+        let extra_slot_field = slot.replace("\"d\"", "\"d\",\"plaintext_key\":\"x\"");
+        assert_eq!(
+            code(&format!(r#"{{"type":"ciphertext","to":"b","nonce":"","ciphertext":"","slots":[{}]}}"#, extra_slot_field)),
+            Some(ErrorCode::InvalidFrame)
+        );
+        assert_eq!(code(r#"{"type":"ciphertext","to":"b","nonce":"","ciphertext":""}"#), Some(ErrorCode::InvalidFrame));
+        assert_eq!(code(r#"{"type":"shutdown"}"#), Some(ErrorCode::InvalidFrame));
+        assert_eq!(code("not json"), Some(ErrorCode::InvalidFrame));
+    }
+
+    #[test]
+    fn versions_default_to_v1_which_is_no_longer_accepted() {
+        let Ok(ClientFrame::Hello(hello)) = parse_client_frame(r#"{"type":"hello","username":"a","token":"t"}"#) else {
+            panic!("hello without versions should parse");
+        };
+        assert_eq!(hello.versions, vec![1]);
+        assert_eq!(negotiate_version(&hello.versions), None);
+
+        assert_eq!(negotiate_version(&[]), None);
+        assert_eq!(negotiate_version(&[MIN_PROTOCOL_VERSION]), Some(MIN_PROTOCOL_VERSION));
+        assert_eq!(negotiate_version(&[1, PROTOCOL_VERSION, PROTOCOL_VERSION + 1]), Some(PROTOCOL_VERSION));
+        assert_eq!(negotiate_version(&[PROTOCOL_VERSION + 1]), None);
+    }
+}
@@ -0,0 +1,171 @@
+// src/queue.rs
+// Store-and-forward queue for recipients that are offline. Only still-encrypted
+// envelopes go in here (exactly the JSON the device would have been sent live);
+// the server can't read them any more than it can read live traffic. Envelopes
+// are queued per device; rows with an empty device_id (receipts) go to whichever
+// of the user's devices connects first. We also remember who each routed message
+// went to, so read receipts are only relayed by someone the message was for.
+use rusqlite::params;
+use crate::db::{self, Db};
+
+/// Most envelopes we hold for one recipient before refusing new ones
+const MAX_QUEUED_PER_RECIPIENT: i64 = 500;
+/// Queued envelopes older than this are dropped (7 days)
+const QUEUE_TTL_SECS: i64 = 7 * 24 * 60 * 60;
+/// How often the sweeper drops expired envelopes
+const QUEUE_SWEEP_INTERVAL_SECS: u64 = 300;
+
+#[derive(Debug)]
+pub enum QueueError {
+    /// Recipient already has MAX_QUEUED_PER_RECIPIENT envelopes waiting
+    Full,
+    Db(rusqlite::Error),
+}
+
+impl std::fmt::Display for QueueError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QueueError::Full => write!(f, "offline queue full"),
+            QueueError::Db(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for QueueError {
+    fn from(e: rusqlite::Error) -> Self {
+        QueueError::Db(e)
+    }
+}
+
+/// Hold `envelope` until `recipient` comes online on `device_id` ("" = any device)
+pub fn enqueue(db: &Db, recipient: &str, device_id: &str, envelope: &str) -> Result<(), QueueError> {
+    let now = db::unix_now();
+    let conn = db.lock().unwrap();
+    let queued: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM offline_queue WHERE recipient = ?1 AND expires_at > ?2",
+        params![recipient, now],
+        |row| row.get(0),
+    )?;
+    if queued >= MAX_QUEUED_PER_RECIPIENT {
+        return Err(QueueError::Full);
+    }
+    conn.execute(
+        "INSERT INTO offline_queue (recipient, device_id, envelope, created_at, expires_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![recipient, device_id, envelope, now, now + QUEUE_TTL_SECS],
+    )?;
+    Ok(())
+}
+
+/// Unexpired envelopes waiting for `recipient`'s device `device_id` (or for any of
+/// their devices), oldest first, as (id, envelope)
+pub fn pending(db: &Db, recipient: &str, device_id: &str) -> rusqlite::Result<Vec<(i64, String)>> {
+    let conn = db.lock().unwrap();
+    let mut stmt = conn.prepare(
+        "SELECT id, envelope FROM offline_queue
+         WHERE recipient = ?1 AND (device_id = ?2 OR device_id = '') AND expires_at > ?3 ORDER BY id",
+    )?;
+    let rows = stmt.query_map(params![recipient, device_id, db::unix_now()], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    rows.collect()
+}
+
+/// Drop an envelope once it has been handed to the recipient's socket
+pub fn remove(db: &Db, id: i64) -> rusqlite::Result<()> {
+    let conn = db.lock().unwrap();
+    conn.execute("DELETE FROM offline_queue WHERE id = ?1", params![id])?;
+    Ok(())
+}
+
+/// Remember that message `mid` from `sender` was routed to `recipient`, so their
+/// read receipt can be relayed later. Kept as long as queued envelopes are.
+pub fn record_route(db: &Db, mid: &str, sender: &str, recipient: &str) -> rusqlite::Result<()> {
+    let conn = db.lock().unwrap();
+    conn.execute(
+        "INSERT OR REPLACE INTO routed_messages (mid, sender, recipient, expires_at) VALUES (?1, ?2, ?3, ?4)",
+        params![mid, sender, recipient, db::unix_now() + QUEUE_TTL_SECS],
+    )?;
+    Ok(())
+}
+
+/// Whether `recipient` was sent message `mid` by `sender` (and hasn't acked it
+/// yet). The route is used up, so each recipient acks a message at most once.
+pub fn take_route(db: &Db, mid: &str, sender: &str, recipient: &str) -> rusqlite::Result<bool> {
+    let conn = db.lock().unwrap();
+    let removed = conn.execute(
+        "DELETE FROM routed_messages WHERE mid = ?1 AND sender = ?2 AND recipient = ?3 AND expires_at > ?4",
+        params![mid, sender, recipient, db::unix_now()],
+    )?;
+    Ok(removed > 0)
+}
+
+/// Periodically drop expired envelopes and routes
+pub fn spawn_queue_sweeper(db: Db) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(QUEUE_SWEEP_INTERVAL_SECS));
+        loop {
+            interval.tick().await;
+            let conn = db.lock().unwrap();
+            let now = db::unix_now();
+            match conn.execute("DELETE FROM offline_queue WHERE expires_at <= ?1", params![now]) {
+                Ok(0) => {}
+                Ok(removed) => println!("[QUEUE] Swept {} expired envelope(s)", removed),
+                Err(e) => println!("[QUEUE] Sweep failed: {}", e),
+            }
+            if let Err(e) = conn.execute("DELETE FROM routed_messages WHERE expires_at <= ?1", params![now]) {
+                println!("[QUEUE] Route sweep failed: {}", e);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_envelopes_past_the_per_recipient_cap() {
+        let db = db::open(":memory:").unwrap();
+        for i in 0..MAX_QUEUED_PER_RECIPIENT {
+            enqueue(&db, "bob", "phone", &format!("envelope {}", i)).unwrap();
+        }
+        assert!(matches!(enqueue(&db, "bob", "", "one more"), Err(QueueError::Full)));
+        // Other recipients have their own cap
+        enqueue(&db, "carol", "phone", "hi").unwrap();
+
+        let (id, first) = pending(&db, "bob", "phone").unwrap().remove(0);
+        assert_eq!(first, "envelope 0");
+        remove(&db, id).unwrap();
+        enqueue(&db, "bob", "phone", "fits again").unwrap();
+    }
+
+    #[test]
+    fn expired_envelopes_are_neither_pending_nor_counted() {
+        let db = db::open(":memory:").unwrap();
+        enqueue(&db, "bob", "phone", "old").unwrap();
+        enqueue(&db, "bob", "laptop", "other device").unwrap();
+        enqueue(&db, "bob", "", "receipt").unwrap();
+        let pending_for = |device| -> Vec<String> { pending(&db, "bob", device).unwrap().into_iter().map(|(_, e)| e).collect() };
+        assert_eq!(pending_for("phone"), vec!["old", "receipt"]);
+
+        db.lock()
+            .unwrap()
+            .execute("UPDATE offline_queue SET expires_at = ?1 WHERE envelope = 'old'", params![db::unix_now() - QUEUE_TTL_SECS])
+            .unwrap();
+        assert_eq!(pending_for("phone"), vec!["receipt"]);
+        let counted: i64 = db
+            .lock()
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM offline_queue WHERE expires_at > ?1", params![db::unix_now()], |row| row.get(0))
+            .unwrap();
+        assert_eq!(counted, 2);
+    }
+
+    #[test]
+    fn routes_are_used_up_by_the_recipient_only() {
+        let db = db::open(":memory:").unwrap();
+        record_route(&db, "m1", "alice", "bob").unwrap();
+        assert!(!take_route(&db, "m1", "alice", "mallory").unwrap());
+        assert!(!take_route(&db, "m1", "bob", "alice").unwrap());
+        assert!(take_route(&db, "m1", "alice", "bob").unwrap());
+        assert!(!take_route(&db, "m1", "alice", "bob").unwrap());
+    }
+}
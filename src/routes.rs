@@ -1,37 +1,217 @@
-// Add Kyber public key endpoints
+// Kyber public key directory endpoints (keys live in keydir / SQLite)
 use axum::Json;
-use std::sync::Arc;
+use axum::extract::Path;
+use axum::http::StatusCode;
 use std::collections::HashMap;
+use crate::crypto::KemVersion;
+use crate::keydir::{self, KeyRecord};
 
-// In-memory store for demo (replace with DB for production)
-static KYBER_PUBKEYS: once_cell::sync::Lazy<Arc<std::sync::Mutex<HashMap<String, String>>>> = once_cell::sync::Lazy::new(|| Arc::new(std::sync::Mutex::new(HashMap::new())));
+#[derive(Deserialize)]
+pub struct PubkeyReq {
+    pub username: String,
+    pub pubkey: String,
+    /// Device that holds the secret key (same id it uses in the ws hello)
+    #[serde(default)]
+    pub device_id: String,
+    /// base64 X25519 key, from devices that support the hybrid KEM
+    #[serde(default)]
+    pub x25519_pubkey: Option<String>,
+    /// base64 ML-DSA-65 identity key, from devices that sign their envelopes
+    #[serde(default)]
+    pub sig_pubkey: Option<String>,
+    /// KEM `pubkey` is for: 1 = Kyber1024 (the default), 3 = ML-KEM-768,
+    /// 4 = ML-KEM-1024
+    #[serde(default = "default_key_kem")]
+    pub kem: u8,
+}
 
-pub async fn post_pubkey(Json(payload): Json<HashMap<String, String>>) -> axum::response::Result<String> {
-    let username = payload.get("username").cloned().unwrap_or_default();
-    let pubkey = payload.get("pubkey").cloned().unwrap_or_default();
-    if !username.is_empty() && !pubkey.is_empty() {
-        KYBER_PUBKEYS.lock().unwrap().insert(username, pubkey);
-        Ok("ok".to_string())
-    } else {
-        Ok("missing username or pubkey".to_string())
+fn default_key_kem() -> u8 {
+    KemVersion::Kyber1024 as u8
+}
+
+#[derive(Serialize, Default)]
+pub struct PubkeyResp {
+    pub ok: bool,
+    pub key_id: Option<String>,
+    pub fingerprint: Option<String>,
+    pub msg: Option<String>,
+}
+
+fn pubkey_error(status: StatusCode, msg: &str) -> (StatusCode, Json<PubkeyResp>) {
+    (status, Json(PubkeyResp { ok: false, msg: Some(msg.to_string()), ..Default::default() }))
+}
+
+/// Publish the KEM public key (Kyber1024 or ML-KEM, per `kem`; and, for clients
+/// that support them, the X25519 and ML-DSA identity keys) of one of the caller's
+/// devices. The X25519 key is only accepted alongside a Kyber1024 key, since the
+/// hybrid KEM is built on Kyber1024. Requires
+/// `Authorization: Bearer <token>` and the username in the body must match the
+/// token's owner.
+pub async fn post_pubkey(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(payload): Json<PubkeyReq>,
+) -> (StatusCode, Json<PubkeyResp>) {
+    let authed = match auth::user_from_headers(&state.db, &headers).await {
+        Some(u) => u,
+        None => return pubkey_error(StatusCode::UNAUTHORIZED, "invalid or missing token"),
+    };
+    let username = auth::normalize_username(&payload.username);
+    if username.is_empty() || payload.pubkey.is_empty() {
+        return pubkey_error(StatusCode::BAD_REQUEST, "missing username or pubkey");
+    }
+    if username != authed {
+        println!("[PUBKEY] Rejected: '{}' tried to publish a key for '{}'", authed, username);
+        return pubkey_error(StatusCode::FORBIDDEN, "cannot publish a key for another user");
+    }
+    if !crate::protocol::valid_device_id(&payload.device_id) {
+        return pubkey_error(StatusCode::BAD_REQUEST, "missing or invalid device_id");
+    }
+    let device_id = payload.device_id.as_str();
+    let kem = match KemVersion::from_u8(payload.kem) {
+        Some(kem @ (KemVersion::Kyber1024 | KemVersion::MlKem768 | KemVersion::MlKem1024)) => kem,
+        _ => return pubkey_error(StatusCode::BAD_REQUEST, &format!("unsupported kem {} for a device key", payload.kem)),
+    };
+    let raw = match base64::engine::general_purpose::STANDARD.decode(&payload.pubkey) {
+        Ok(raw) if kem.validate_public_key(&raw) => raw,
+        _ => return pubkey_error(StatusCode::BAD_REQUEST, &format!("pubkey is not a valid {:?} public key", kem)),
+    };
+    if payload.x25519_pubkey.is_some() && kem != KemVersion::Kyber1024 {
+        return pubkey_error(StatusCode::BAD_REQUEST, "x25519_pubkey is only used with kem 1");
+    }
+    if let Some(x25519) = &payload.x25519_pubkey {
+        match base64::engine::general_purpose::STANDARD.decode(x25519) {
+            Ok(raw) if raw.len() == crate::crypto::hybrid::X25519_KEY_LEN => {}
+            _ => return pubkey_error(StatusCode::BAD_REQUEST, "x25519_pubkey is not a valid X25519 public key"),
+        }
     }
+    if let Some(sig) = &payload.sig_pubkey {
+        match base64::engine::general_purpose::STANDARD.decode(sig) {
+            Ok(raw) if crate::crypto::identity::validate_verifying_key(&raw) => {}
+            _ => return pubkey_error(StatusCode::BAD_REQUEST, "sig_pubkey is not a valid ML-DSA-65 public key"),
+        }
+    }
+    let previous = keydir::current_key(&state.db, &username, device_id).unwrap_or(None);
+    let key = keydir::NewKey {
+        pubkey_b64: &payload.pubkey,
+        raw_key: &raw,
+        kem: kem as u8,
+        x25519_b64: payload.x25519_pubkey.as_deref(),
+        sig_b64: payload.sig_pubkey.as_deref(),
+    };
+    match keydir::publish_key(&state.db, &username, device_id, &key) {
+        Ok(key) => {
+            println!("[PUBKEY] user='{}' device='{}' key_id={}", username, device_id, key.key_id);
+            if previous.as_ref().map(|p| &p.fingerprint) != Some(&key.fingerprint) {
+                ws::broadcast_key_changed(&state, &username, device_id, previous.as_ref(), Some(&key)).await;
+            }
+            (StatusCode::OK, Json(PubkeyResp { ok: true, key_id: Some(key.key_id), fingerprint: Some(key.fingerprint), msg: None }))
+        }
+        Err(keydir::PublishError::Revoked) => pubkey_error(StatusCode::CONFLICT, "key has been revoked"),
+        Err(keydir::PublishError::KemMismatch) => pubkey_error(StatusCode::CONFLICT, "key was published for a different kem"),
+        Err(e) => {
+            println!("[PUBKEY] Failed to store key for '{}': {}", username, e);
+            pubkey_error(StatusCode::INTERNAL_SERVER_ERROR, "failed to store key")
+        }
+    }
+}
+
+/// Current key of every device of every user: { username: [KeyRecord, ...] }
+pub async fn get_pubkeys(State(state): State<AppState>) -> Result<Json<HashMap<String, Vec<KeyRecord>>>, StatusCode> {
+    let keys = keydir::current_keys(&state.db).map_err(|e| {
+        println!("[PUBKEY] Listing keys failed: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let mut by_user: HashMap<String, Vec<KeyRecord>> = HashMap::new();
+    for key in keys {
+        by_user.entry(key.username.clone()).or_default().push(key);
+    }
+    Ok(Json(by_user))
+}
+
+#[derive(Serialize)]
+pub struct UserKeysResp {
+    pub username: String,
+    /// Current key of each device, most recently published first
+    pub devices: Vec<KeyRecord>,
+    /// Every key ever published, newest first (revoked ones included)
+    pub keys: Vec<KeyRecord>,
 }
 
-pub async fn get_pubkeys() -> axum::response::Result<Json<HashMap<String, String>>> {
-    let keys = KYBER_PUBKEYS.lock().unwrap().clone();
-    Ok(Json(keys))
+/// Current device keys plus full key history for one user
+pub async fn get_user_pubkeys(State(state): State<AppState>, Path(user): Path<String>) -> Result<Json<UserKeysResp>, StatusCode> {
+    let username = auth::normalize_username(&user);
+    let lookup = keydir::device_keys(&state.db, &username)
+        .and_then(|devices| Ok((devices, keydir::key_history(&state.db, &username)?)));
+    let (devices, keys) = lookup.map_err(|e| {
+        println!("[PUBKEY] Key lookup for '{}' failed: {}", username, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    if keys.is_empty() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    Ok(Json(UserKeysResp { username, devices, keys }))
+}
+
+/// Revoke one of the caller's own keys
+pub async fn revoke_pubkey(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((user, key_id)): Path<(String, String)>,
+) -> (StatusCode, Json<PubkeyResp>) {
+    let authed = match auth::user_from_headers(&state.db, &headers).await {
+        Some(u) => u,
+        None => return pubkey_error(StatusCode::UNAUTHORIZED, "invalid or missing token"),
+    };
+    if auth::normalize_username(&user) != authed {
+        return pubkey_error(StatusCode::FORBIDDEN, "cannot revoke another user's key");
+    }
+    let device_id = match keydir::key(&state.db, &authed, &key_id) {
+        Ok(Some(key)) => key.device_id,
+        Ok(None) => return pubkey_error(StatusCode::NOT_FOUND, "no such live key"),
+        Err(e) => {
+            println!("[PUBKEY] Key lookup for '{}' failed: {}", authed, e);
+            return pubkey_error(StatusCode::INTERNAL_SERVER_ERROR, "failed to revoke key");
+        }
+    };
+    let previous = keydir::current_key(&state.db, &authed, &device_id).unwrap_or(None);
+    match keydir::revoke_key(&state.db, &authed, &key_id) {
+        Ok(true) => {
+            println!("[PUBKEY] user='{}' revoked key_id={}", authed, key_id);
+            let current = keydir::current_key(&state.db, &authed, &device_id).unwrap_or(None);
+            if previous.as_ref().map(|p| &p.fingerprint) != current.as_ref().map(|c| &c.fingerprint) {
+                ws::broadcast_key_changed(&state, &authed, &device_id, previous.as_ref(), current.as_ref()).await;
+            }
+            (StatusCode::OK, Json(PubkeyResp { ok: true, key_id: Some(key_id), ..Default::default() }))
+        }
+        Ok(false) => pubkey_error(StatusCode::NOT_FOUND, "no such live key"),
+        Err(e) => {
+            println!("[PUBKEY] Revoke failed for '{}': {}", authed, e);
+            pubkey_error(StatusCode::INTERNAL_SERVER_ERROR, "failed to revoke key")
+        }
+    }
+}
+/// Server Ed25519 public key that signs key_changed events
+pub async fn get_server_key(State(state): State<AppState>) -> Json<serde_json::Value> {
+    Json(serde_json::json!({
+        "alg": "ed25519",
+        "key": crate::signing::public_key_b64(&state.server_key),
+    }))
 }
 // src/routes.rs
 use axum::{
-    routing::{get, post},
-    Router, extract::State,
+    extract::{FromRequest, Multipart, State},
+    http::header::{CONTENT_LENGTH, CONTENT_TYPE},
+    http::HeaderMap,
     response::{IntoResponse},
 };
 use base64::Engine;
 use crate::state::AppState;
 use serde::{Deserialize, Serialize};
 use crate::auth;
+use crate::groups::{self, GroupError, GroupInfo, Member};
 use crate::ipfs;
+use crate::ws;
 
 #[derive(Deserialize)]
 pub struct LoginReq {
@@ -46,16 +226,9 @@ pub struct LoginResp {
     pub msg: Option<String>,
 }
 
-pub fn routes(state: crate::state::AppState) -> Router {
-    Router::new()
-        .route("/", get(index))
-        .route("/login", post(login_handler))
-        .route("/ipfs/add", post(ipfs_add))
-    // Kyber public key exchange endpoints (browser-provided)
-    .route("/pubkey", post(post_pubkey))
-    .route("/pubkeys", get(get_pubkeys))
-        .route("/chat", get(chat))
-        .with_state(state)
+fn session_error() -> (axum::http::StatusCode, axum::Json<LoginResp>) {
+    let resp = LoginResp { ok: false, token: None, msg: Some("could not create a session, try again".into()) };
+    (axum::http::StatusCode::INTERNAL_SERVER_ERROR, axum::Json(resp))
 }
 
 /// serve static index.html
@@ -65,9 +238,12 @@ pub async fn index() -> impl IntoResponse {
 
 pub async fn login_handler(State(state): State<AppState>, Json(payload): Json<LoginReq>) -> impl IntoResponse {
     println!("[LOGIN] Attempt user='{}'", payload.username);
-    if auth::verify_login(&payload.username, &payload.password).await {
-        let token = auth::create_token_for_user(&state.tokens, &payload.username).await;
-        println!("[LOGIN] Success user='{}' token='{}'", payload.username, token);
+    if auth::verify_login(&state.db, &payload.username, &payload.password).await {
+        let username = auth::normalize_username(&payload.username);
+        let Ok(token) = auth::create_token_for_user(&state.db, &username).await else {
+            return session_error().into_response();
+        };
+        println!("[LOGIN] Success user='{}'", username);
         let resp = LoginResp { ok: true, token: Some(token), msg: None };
         (axum::http::StatusCode::OK, axum::Json(resp)).into_response()
     } else {
@@ -77,32 +253,475 @@ pub async fn login_handler(State(state): State<AppState>, Json(payload): Json<Lo
     }
 }
 
-pub async fn chat() -> impl IntoResponse {
-    axum::response::Html(include_str!("../static/chat.html"))
+/// Create an account and log it in straight away (same response shape as /login)
+pub async fn register_handler(State(state): State<AppState>, Json(payload): Json<LoginReq>) -> impl IntoResponse {
+    println!("[REGISTER] Attempt user='{}'", payload.username);
+    match auth::register_user(&state.db, &payload.username, &payload.password).await {
+        Ok(username) => {
+            println!("[REGISTER] Created user='{}'", username);
+            // The account exists now; the client can still log in normally
+            let Ok(token) = auth::create_token_for_user(&state.db, &username).await else {
+                return session_error().into_response();
+            };
+            let resp = LoginResp { ok: true, token: Some(token), msg: None };
+            (axum::http::StatusCode::CREATED, axum::Json(resp)).into_response()
+        }
+        Err(e) => {
+            println!("[REGISTER] Failed user='{}': {}", payload.username, e);
+            let status = match e {
+                auth::RegisterError::UsernameTaken => axum::http::StatusCode::CONFLICT,
+                auth::RegisterError::Internal(_) => axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+                _ => axum::http::StatusCode::BAD_REQUEST,
+            };
+            let resp = LoginResp { ok: false, token: None, msg: Some(e.to_string()) };
+            (status, axum::Json(resp)).into_response()
+        }
+    }
 }
 
-/// Simple IPFS add endpoint that accepts {"data": "<base64>"} and returns { cid }
-#[derive(Deserialize)]
-pub struct IpfsAddReq {
-    data_b64: String,
+#[derive(Serialize)]
+struct LogoutResp {
+    ok: bool,
+    msg: Option<String>,
+}
+
+/// Revoke the bearer token and close the user's live socket
+pub async fn logout_handler(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    let token = match auth::bearer_token(&headers) {
+        Some(t) => t,
+        None => return (axum::http::StatusCode::UNAUTHORIZED, Json(LogoutResp { ok: false, msg: Some("missing bearer token".into()) })),
+    };
+    match auth::revoke_token(&state.db, token).await {
+        Some(username) => {
+            println!("[LOGOUT] user='{}'", username);
+            ws::close_session(&state, &username, token).await;
+            (axum::http::StatusCode::OK, Json(LogoutResp { ok: true, msg: None }))
+        }
+        None => (axum::http::StatusCode::UNAUTHORIZED, Json(LogoutResp { ok: false, msg: Some("invalid or expired token".into()) })),
+    }
+}
+
+/// Exchange a live bearer token for a fresh one
+pub async fn refresh_handler(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    let refreshed = match auth::bearer_token(&headers) {
+        Some(old) => auth::refresh_token(&state.db, old).await.map(|new| new.map(|new| (old, new))),
+        None => Ok(None),
+    };
+    match refreshed {
+        Ok(Some((old, token))) => {
+            ws::rebind_session(&state, old, &token).await;
+            (axum::http::StatusCode::OK, Json(LoginResp { ok: true, token: Some(token), msg: None }))
+        }
+        Ok(None) => (axum::http::StatusCode::UNAUTHORIZED, Json(LoginResp { ok: false, token: None, msg: Some("invalid or expired token".into()) })),
+        Err(_) => session_error(),
+    }
+}
+
+pub async fn chat() -> impl IntoResponse {
+    axum::response::Html(include_str!("../static/chat.html"))
 }
 
 #[derive(Serialize)]
-struct IpfsAddResp {
+pub struct IpfsAddResp {
     ok: bool,
     cid: Option<String>,
     msg: Option<String>,
 }
 
-pub async fn ipfs_add(State(_state): State<AppState>, Json(payload): Json<IpfsAddReq>) -> impl IntoResponse {
-    // Decode base64
-    let bytes = match base64::engine::general_purpose::STANDARD.decode(&payload.data_b64) {
-        Ok(b) => b,
-        Err(e) => return (axum::http::StatusCode::BAD_REQUEST, Json(IpfsAddResp { ok:false, cid:None, msg: Some(format!("bad base64: {}", e))})),
+/// 503 while the daemon can't be reached, 502 when it answers with an error
+fn ipfs_error_status(e: &ipfs::IpfsError) -> StatusCode {
+    match e {
+        ipfs::IpfsError::Unreachable(_) => StatusCode::SERVICE_UNAVAILABLE,
+        ipfs::IpfsError::BadResponse(_) => StatusCode::BAD_GATEWAY,
+    }
+}
+
+fn ipfs_add_error(status: StatusCode, msg: &str) -> (StatusCode, Json<IpfsAddResp>) {
+    (status, Json(IpfsAddResp { ok: false, cid: None, msg: Some(msg.to_string()) }))
+}
+
+/// IPFS add endpoint for signed-in users; returns { cid }. The body is either the
+/// raw bytes (`application/octet-stream`) or `multipart/form-data` with a `file`
+/// field, and is streamed on to the node as it arrives, up to
+/// `IpfsClient::max_upload_bytes`. Attachments are encrypted in the browser
+/// before they get here.
+pub async fn ipfs_add(State(state): State<AppState>, request: axum::http::Request<axum::body::Body>) -> (StatusCode, Json<IpfsAddResp>) {
+    let Some(authed) = auth::user_from_headers(&state.db, request.headers()).await else {
+        return ipfs_add_error(StatusCode::UNAUTHORIZED, "invalid or missing token");
+    };
+    let limit = state.ipfs.max_upload_bytes();
+    let declared = request.headers().get(CONTENT_LENGTH).and_then(|v| v.to_str().ok()?.parse::<u64>().ok());
+    if declared.is_some_and(|len| len > limit) {
+        return ipfs_add_error(StatusCode::PAYLOAD_TOO_LARGE, &format!("upload larger than {} bytes", limit));
+    }
+    let content_type = request.headers().get(CONTENT_TYPE).and_then(|v| v.to_str().ok()).unwrap_or("").to_ascii_lowercase();
+
+    let (tx, rx) = futures::channel::mpsc::channel(ipfs::UPLOAD_QUEUE);
+    let ipfs = state.ipfs.clone();
+    let upload = ipfs.add_stream(rx);
+    let (forwarded, added) = if content_type.starts_with("multipart/form-data") {
+        let mut multipart = match Multipart::from_request(request, &state).await {
+            Ok(m) => m,
+            Err(e) => return ipfs_add_error(StatusCode::BAD_REQUEST, &e.body_text()),
+        };
+        let forward = async {
+            let err = loop {
+                match multipart.next_field().await {
+                    Ok(Some(field)) if field.name() == Some("file") => return ipfs::forward_upload(field, tx, limit).await,
+                    Ok(Some(_)) => continue,
+                    Ok(None) => break ipfs::UploadError::BadBody("missing file field".into()),
+                    Err(e) => break ipfs::UploadError::BadBody(e.to_string()),
+                }
+            };
+            // Dropping `tx` would end the upload cleanly and pin an empty file
+            ipfs::abort_upload(tx, &err).await;
+            Err(err)
+        };
+        tokio::join!(forward, upload)
+    } else if content_type.starts_with("application/octet-stream") {
+        tokio::join!(ipfs::forward_upload(request.into_body(), tx, limit), upload)
+    } else {
+        return ipfs_add_error(StatusCode::UNSUPPORTED_MEDIA_TYPE, "send application/octet-stream or multipart/form-data");
+    };
+
+    match forwarded.and_then(|len| added.map(|cid| (len, cid)).map_err(ipfs::UploadError::Ipfs)) {
+        Ok((len, cid)) => {
+            println!("[IPFS] '{}' added {} bytes as {}", authed, len, cid);
+            (StatusCode::OK, Json(IpfsAddResp { ok: true, cid: Some(cid), msg: None }))
+        }
+        Err(e) => {
+            println!("[IPFS] Upload from '{}' failed: {}", authed, e);
+            let status = match e {
+                ipfs::UploadError::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+                ipfs::UploadError::BadBody(_) => StatusCode::BAD_REQUEST,
+                ipfs::UploadError::Ipfs(ref e) => ipfs_error_status(e),
+            };
+            ipfs_add_error(status, &e.to_string())
+        }
+    }
+}
+
+/// Download proxy for signed-in users: streams the raw (still encrypted) bytes
+/// behind a CID from the local IPFS node, so browsers never talk to the node's
+/// API themselves
+pub async fn ipfs_get(State(state): State<AppState>, headers: HeaderMap, Path(cid): Path<String>) -> axum::response::Response {
+    if auth::user_from_headers(&state.db, &headers).await.is_none() {
+        return (StatusCode::UNAUTHORIZED, "invalid or missing token").into_response();
+    }
+    if !ipfs::is_valid_cid(&cid) {
+        return (StatusCode::BAD_REQUEST, "bad cid").into_response();
+    }
+    let res = match state.ipfs.cat(&cid).await {
+        Ok(res) => res,
+        Err(e) => {
+            println!("[IPFS] cat {} failed: {}", cid, e);
+            return (ipfs_error_status(&e), "ipfs fetch failed").into_response();
+        }
     };
+    let body = futures::stream::unfold(Some(res), |res| async move {
+        let mut res = res?;
+        match res.chunk().await {
+            Ok(Some(chunk)) => Some((Ok(chunk), Some(res))),
+            Ok(None) => None,
+            // Yield the error and stop; the client sees a truncated body
+            Err(e) => Some((Err(e), None)),
+        }
+    });
+    (
+        [
+            (axum::http::header::CONTENT_TYPE, "application/octet-stream"),
+            // Content behind a CID never changes, but it's still only for this user
+            (axum::http::header::CACHE_CONTROL, "private, max-age=31536000, immutable"),
+        ],
+        axum::body::StreamBody::new(body),
+    )
+        .into_response()
+}
+
+// Group endpoints (membership lives in groups / SQLite). All of them need
+// `Authorization: Bearer <token>`; only members can see a group.
+
+#[derive(Deserialize)]
+pub struct CreateGroupReq {
+    pub name: String,
+    #[serde(default)]
+    pub members: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct GroupMemberReq {
+    pub username: String,
+}
+
+#[derive(Serialize, Default)]
+pub struct GroupResp {
+    pub ok: bool,
+    pub group: Option<GroupInfo>,
+    pub msg: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct GroupListResp {
+    pub ok: bool,
+    pub groups: Vec<GroupInfo>,
+}
+
+#[derive(Serialize)]
+pub struct GroupMembersResp {
+    pub ok: bool,
+    pub owner: String,
+    pub members: Vec<Member>,
+}
+
+fn group_error(e: GroupError) -> (StatusCode, Json<GroupResp>) {
+    let status = match e {
+        GroupError::InvalidName(_) | GroupError::UnknownUser(_) | GroupError::Full => StatusCode::BAD_REQUEST,
+        GroupError::NotFound | GroupError::NotMember => StatusCode::NOT_FOUND,
+        GroupError::NotOwner => StatusCode::FORBIDDEN,
+        GroupError::AlreadyMember => StatusCode::CONFLICT,
+        GroupError::Db(ref err) => {
+            println!("[GROUP] Database error: {}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+    (status, Json(GroupResp { ok: false, msg: Some(e.to_string()), ..Default::default() }))
+}
+
+fn group_ok(status: StatusCode, group: Option<GroupInfo>) -> (StatusCode, Json<GroupResp>) {
+    (status, Json(GroupResp { ok: true, group, msg: None }))
+}
+
+fn unauthorized() -> (StatusCode, Json<GroupResp>) {
+    (StatusCode::UNAUTHORIZED, Json(GroupResp { ok: false, msg: Some("invalid or missing token".into()), ..Default::default() }))
+}
+
+/// Create a group owned by the caller: { name, members: [usernames] }
+pub async fn create_group(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(payload): Json<CreateGroupReq>,
+) -> (StatusCode, Json<GroupResp>) {
+    let Some(authed) = auth::user_from_headers(&state.db, &headers).await else {
+        return unauthorized();
+    };
+    match groups::create_group(&state.db, &authed, &payload.name, &payload.members) {
+        Ok(group) => {
+            println!("[GROUP] '{}' created group {} '{}'", authed, group.id, group.name);
+            ws::notify_group_changed(&state, &group, "created", &authed, &authed).await;
+            group_ok(StatusCode::CREATED, Some(group))
+        }
+        Err(e) => group_error(e),
+    }
+}
+
+/// Groups the caller is a member of
+pub async fn list_groups(State(state): State<AppState>, headers: HeaderMap) -> Result<Json<GroupListResp>, StatusCode> {
+    let authed = auth::user_from_headers(&state.db, &headers).await.ok_or(StatusCode::UNAUTHORIZED)?;
+    let groups = groups::groups_for_user(&state.db, &authed).map_err(|e| {
+        println!("[GROUP] Listing groups for '{}' failed: {}", authed, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Json(GroupListResp { ok: true, groups }))
+}
+
+/// One group (members only)
+pub async fn get_group(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(group_id): Path<String>,
+) -> (StatusCode, Json<GroupResp>) {
+    let Some(authed) = auth::user_from_headers(&state.db, &headers).await else {
+        return unauthorized();
+    };
+    match groups::group_info(&state.db, &group_id, &authed) {
+        Ok(group) => group_ok(StatusCode::OK, Some(group)),
+        Err(e) => group_error(e),
+    }
+}
+
+/// Member list of one group (members only)
+pub async fn get_group_members(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(group_id): Path<String>,
+) -> Result<Json<GroupMembersResp>, (StatusCode, Json<GroupResp>)> {
+    let authed = auth::user_from_headers(&state.db, &headers).await.ok_or_else(unauthorized)?;
+    let group = groups::group_info(&state.db, &group_id, &authed).map_err(group_error)?;
+    Ok(Json(GroupMembersResp { ok: true, owner: group.owner, members: group.members }))
+}
+
+/// Add a user to a group the caller is in
+pub async fn invite_to_group(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(group_id): Path<String>,
+    Json(payload): Json<GroupMemberReq>,
+) -> (StatusCode, Json<GroupResp>) {
+    let Some(authed) = auth::user_from_headers(&state.db, &headers).await else {
+        return unauthorized();
+    };
+    let username = auth::normalize_username(&payload.username);
+    match groups::invite(&state.db, &group_id, &authed, &username) {
+        Ok(group) => {
+            ws::notify_group_changed(&state, &group, "joined", &username, &authed).await;
+            group_ok(StatusCode::OK, Some(group))
+        }
+        Err(e) => group_error(e),
+    }
+}
+
+/// The caller leaves a group
+pub async fn leave_group(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(group_id): Path<String>,
+) -> (StatusCode, Json<GroupResp>) {
+    let Some(authed) = auth::user_from_headers(&state.db, &headers).await else {
+        return unauthorized();
+    };
+    match groups::leave(&state.db, &group_id, &authed) {
+        Ok(Some(group)) => {
+            ws::notify_group_changed(&state, &group, "left", &authed, &authed).await;
+            group_ok(StatusCode::OK, Some(group))
+        }
+        // Last member left; the group is gone
+        Ok(None) => group_ok(StatusCode::OK, None),
+        Err(e) => group_error(e),
+    }
+}
+
+/// The owner removes a member
+pub async fn kick_from_group(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(group_id): Path<String>,
+    Json(payload): Json<GroupMemberReq>,
+) -> (StatusCode, Json<GroupResp>) {
+    let Some(authed) = auth::user_from_headers(&state.db, &headers).await else {
+        return unauthorized();
+    };
+    let username = auth::normalize_username(&payload.username);
+    match groups::kick(&state.db, &group_id, &authed, &username) {
+        Ok(group) => {
+            ws::notify_group_changed(&state, &group, "kicked", &username, &authed).await;
+            group_ok(StatusCode::OK, Some(group))
+        }
+        Err(e) => group_error(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::HttpBody;
+    use axum::http::header::{AUTHORIZATION, CACHE_CONTROL, CONTENT_TYPE};
+    use serde_json::Value;
+    use sha2::{Digest, Sha256};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::ipfs::mock::{client, serve, Reply};
+
+    const CID: &str = "QmYwAPJzv5CZsnA625s3Xf2nemtYgPpHdWEz79ojWnPbdG";
+
+    /// Attachments as chat.html encrypts them (written by attachment_js.mjs)
+    const ATTACHMENT_JS_FIXTURE: &str = include_str!("crypto/testdata/attachment_js.json");
+
+    /// State whose IPFS client talks to a mock daemon on `listener`, and a
+    /// signed-in user's token
+    async fn test_state(listener: &TcpListener) -> (AppState, String) {
+        let db = crate::db::open(":memory:").unwrap();
+        db.lock()
+            .unwrap()
+            .execute("INSERT INTO users (username, password_hash, created_at) VALUES ('alice', 'x', 0)", [])
+            .unwrap();
+        let token = auth::create_token_for_user(&db, "alice").await.unwrap();
+        let server_key = crate::signing::load_or_create(&db).unwrap();
+        (AppState::new(db, server_key, client(listener.local_addr().unwrap())), token)
+    }
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, format!("Bearer {}", token).parse().unwrap());
+        headers
+    }
+
+    async fn get(state: &AppState, headers: HeaderMap, cid: &str) -> axum::response::Response {
+        ipfs_get(State(state.clone()), headers, Path(cid.to_string())).await
+    }
+
+    async fn body_bytes(res: axum::response::Response) -> Vec<u8> {
+        let mut body = res.into_body();
+        let mut out = Vec::new();
+        while let Some(chunk) = body.data().await {
+            out.extend_from_slice(&chunk.unwrap());
+        }
+        out
+    }
+
+    #[tokio::test]
+    async fn ipfs_get_needs_a_token_and_a_plain_cid() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (state, token) = test_state(&listener).await;
+        let connections = serve(listener, Reply::Close);
+
+        assert_eq!(get(&state, HeaderMap::new(), CID).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(get(&state, bearer("not-a-token"), CID).await.status(), StatusCode::UNAUTHORIZED);
+        for cid in ["not a cid", "QmYwAPJzv5CZsnA625s3Xf2nemtYgPpHdWEz79ojWnPbdG/../x", ""] {
+            assert_eq!(get(&state, bearer(&token), cid).await.status(), StatusCode::BAD_REQUEST, "{:?}", cid);
+        }
+        // None of these reached the daemon
+        assert_eq!(connections.load(std::sync::atomic::Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn ipfs_get_maps_daemon_errors() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (state, token) = test_state(&listener).await;
+        let response = "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+        serve(listener, Reply::Respond(response.into()));
+        assert_eq!(get(&state, bearer(&token), CID).await.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    /// A chat.html attachment comes back from the daemon through the proxy byte
+    /// for byte, and opens with the key from the message
+    #[tokio::test]
+    async fn ipfs_get_streams_attachments_from_the_daemon() {
+        let fixture: Value = serde_json::from_str(ATTACHMENT_JS_FIXTURE).unwrap();
+        let attachment = fixture["attachments"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|a| a["meta"]["size"].as_u64().unwrap() as usize > crate::crypto::stream::CHUNK_SIZE)
+            .unwrap();
+        let engine = base64::engine::general_purpose::STANDARD;
+        let blob = engine.decode(attachment["blob"].as_str().unwrap()).unwrap();
+
+        // Sent chunked, in two pieces, as the daemon streams large files
+        let (first, rest) = blob.split_at(blob.len() / 2);
+        let mut response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n".to_vec();
+        for piece in [first, rest] {
+            response.extend_from_slice(format!("{:x}\r\n", piece.len()).as_bytes());
+            response.extend_from_slice(piece);
+            response.extend_from_slice(b"\r\n");
+        }
+        response.extend_from_slice(b"0\r\n\r\n");
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (state, token) = test_state(&listener).await;
+        serve(listener, Reply::Respond(response));
+
+        let res = get(&state, bearer(&token), CID).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[CONTENT_TYPE], "application/octet-stream");
+        assert!(res.headers()[CACHE_CONTROL].to_str().unwrap().starts_with("private"));
+        let body = body_bytes(res).await;
+        assert_eq!(body, blob);
 
-    match ipfs::add_bytes_to_ipfs(bytes).await {
-        Ok(cid) => (axum::http::StatusCode::OK, Json(IpfsAddResp { ok:true, cid:Some(cid), msg:None })),
-        Err(e) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, Json(IpfsAddResp { ok:false, cid:None, msg:Some(format!("{}", e)) })),
+        let meta = &attachment["meta"];
+        let key: [u8; 32] = engine.decode(meta["key"].as_str().unwrap()).unwrap().try_into().unwrap();
+        let mut plaintext = Vec::new();
+        crate::crypto::stream::decrypt_stream(&key, &body[..], &mut plaintext).await.unwrap();
+        assert_eq!(plaintext.len() as u64, meta["size"].as_u64().unwrap());
+        assert_eq!(format!("{:x}", Sha256::digest(&plaintext)), meta["sha256"].as_str().unwrap());
     }
 }
@@ -0,0 +1,43 @@
+// src/signing.rs
+// Server signing key (Ed25519) used to sign events clients must be able to trust,
+// such as key_changed. The key is generated once and kept in SQLite so signatures
+// stay verifiable across restarts; clients fetch the public half from /server-key.
+use std::sync::Arc;
+use base64::Engine;
+use ed25519_dalek::{Signer, SigningKey};
+use rand::RngCore;
+use rusqlite::{params, OptionalExtension};
+use crate::db::{self, Db};
+
+pub type ServerKey = Arc<SigningKey>;
+
+/// Load the server signing key, creating and storing one on first start
+pub fn load_or_create(db: &Db) -> rusqlite::Result<ServerKey> {
+    let conn = db.lock().unwrap();
+    let stored: Option<Vec<u8>> = conn
+        .query_row("SELECT secret FROM server_keys WHERE name = 'signing'", [], |row| row.get(0))
+        .optional()?;
+    if let Some(bytes) = stored {
+        if let Ok(seed) = <[u8; 32]>::try_from(bytes.as_slice()) {
+            return Ok(Arc::new(SigningKey::from_bytes(&seed)));
+        }
+        println!("[SIGNING] Stored server key is malformed, generating a new one");
+    }
+    let mut seed = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut seed);
+    conn.execute(
+        "INSERT OR REPLACE INTO server_keys (name, secret, created_at) VALUES ('signing', ?1, ?2)",
+        params![seed.to_vec(), db::unix_now()],
+    )?;
+    Ok(Arc::new(SigningKey::from_bytes(&seed)))
+}
+
+/// base64 of the 32-byte Ed25519 public key
+pub fn public_key_b64(key: &SigningKey) -> String {
+    base64::engine::general_purpose::STANDARD.encode(key.verifying_key().as_bytes())
+}
+
+/// Sign `message` and return the base64 signature
+pub fn sign_b64(key: &SigningKey, message: &[u8]) -> String {
+    base64::engine::general_purpose::STANDARD.encode(key.sign(message).to_bytes())
+}
@@ -2,34 +2,46 @@
 use std::{collections::HashMap, sync::Arc};
 use tokio::sync::{Mutex, mpsc};
 use axum::extract::ws::Message;
-// For Kyber key management
-pub struct UserKeys {
-    pub public_key: Vec<u8>,
-    pub secret_key: Vec<u8>,
-}
+use crate::db::Db;
+use crate::ipfs::IpfsClient;
+use crate::signing::ServerKey;
 
 pub type Tx = mpsc::UnboundedSender<Message>;
-pub type ClientsMap = Arc<Mutex<HashMap<String, Tx>>>;
+/// One user's connected sockets (connection id -> device socket)
+pub type Devices = HashMap<String, Device>;
+pub type ClientsMap = Arc<Mutex<HashMap<String, Devices>>>;
 
-/// Maps short session tokens -> username
-pub type TokenMap = Arc<Mutex<HashMap<String, String>>>;
+/// A single connected socket (browser tab, phone, ...)
+pub struct Device {
+    pub tx: Tx,
+    /// Device (key holder) this socket belongs to; several tabs of one browser
+    /// share a device id
+    pub device_id: String,
+    /// Session token the device said hello with, so /logout only closes the
+    /// devices of that session
+    pub token: String,
+}
 
 #[derive(Clone)]
 pub struct AppState {
-    /// Connected clients (username -> sender)
+    /// Connected clients (username -> connection id -> device socket). A user is online
+    /// while at least one device is connected.
     pub clients: ClientsMap,
-    /// Login tokens (token -> username)
-    pub tokens: TokenMap,
-    /// Kyber keypairs (username -> keys)
-    pub user_keys: Arc<Mutex<HashMap<String, UserKeys>>>,
+    /// SQLite database (user accounts, sessions, public keys)
+    pub db: Db,
+    /// Signs events clients verify (key changes)
+    pub server_key: ServerKey,
+    /// IPFS daemon that stores (encrypted) attachments
+    pub ipfs: IpfsClient,
 }
 
 impl AppState {
-    pub fn new() -> Self {
+    pub fn new(db: Db, server_key: ServerKey, ipfs: IpfsClient) -> Self {
         AppState {
             clients: Arc::new(Mutex::new(HashMap::new())),
-            tokens: Arc::new(Mutex::new(HashMap::new())),
-            user_keys: Arc::new(Mutex::new(HashMap::new())),
+            db,
+            server_key,
+            ipfs,
         }
     }
 }
@@ -1,9 +1,9 @@
-/// Broadcast a JSON message to all connected clients
-pub async fn broadcast_json(state: &AppState, value: serde_json::Value) {
-    let msg = axum::extract::ws::Message::Text(value.to_string());
+/// Broadcast a frame to all connected clients
+pub async fn broadcast_frame(state: &AppState, frame: &ServerFrame) {
+    let msg = axum::extract::ws::Message::Text(frame.to_json());
     let clients = state.clients.lock().await;
-    for (_, tx) in clients.iter() {
-        let _ = tx.send(msg.clone());
+    for device in clients.values().flat_map(|devices| devices.values()) {
+        let _ = device.tx.send(msg.clone());
     }
 }
 // src/ws.rs
@@ -13,167 +13,156 @@ use axum::{
     response::IntoResponse,
 };
 use futures::{SinkExt, StreamExt};
-use serde::{Deserialize, Serialize};
-use crate::state::AppState;
+use std::collections::HashMap;
+use crate::state::{AppState, Device, Devices, Tx};
+use crate::keydir::{self, KeyRecord};
+use crate::groups::{self, GroupInfo};
+use crate::protocol::{self, Ciphertext, ClientFrame, ErrorCode, GroupCiphertext, KeySlot, Plaintext, ReadAck, ServerFrame};
+use crate::queue;
 // no base64 or server-side key generation needed here; server is a dumb relay for E2EE
 use crate::auth::username_for_token;
 
-#[derive(Debug, Deserialize, Serialize)]
-pub struct Hello {
-    pub r#type: String, // "hello"
-    pub username: String,
-    pub token: String,
-}
-
-#[derive(Debug, Deserialize, Serialize)]
-pub struct ForwardMsg {
-    pub r#type: String, // e.g. "pubkey", "encap", "ciphertext", "ipfs"
-    pub from: String,
-    pub to: Option<String>,
-    pub data: Option<String>,
-    // optional iv, cid, etc, kept in data or as fields if you prefer
-    pub iv: Option<String>,
-    pub cid: Option<String>,
-}
+/// Per-socket channel for envelopes from the offline queue (see `confirm_queued`)
+type QueuedTx = tokio::sync::mpsc::UnboundedSender<QueuedFrame>;
 
 pub async fn ws_handler(ws: WebSocketUpgrade, State(state): State<AppState>) -> impl IntoResponse {
     ws.on_upgrade(move |socket| handle_socket(socket, state))
 }
 
+/// Queue a frame on this socket's outgoing channel
+fn send_frame(tx: &Tx, frame: &ServerFrame) {
+    let _ = tx.send(Message::Text(frame.to_json()));
+}
+
 async fn handle_socket(stream: WebSocket, state: AppState) {
     // Split socket
     let (mut sender, mut receiver) = stream.split();
 
     // Channel for sending messages to this client
     let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<Message>();
+    // Envelopes from the offline queue, which stay queued until written
+    let (queued_tx, mut queued_rx) = tokio::sync::mpsc::unbounded_channel::<QueuedFrame>();
 
-    // Task to forward messages from rx -> socket
+    // Task to forward messages from rx -> socket. The backlog goes first: it is
+    // all handed over before the device is registered for live traffic.
+    let writer_state = state.clone();
     tokio::spawn(async move {
-        while let Some(msg) = rx.recv().await {
-            if sender.send(msg).await.is_err() {
-                break;
+        loop {
+            tokio::select! {
+                biased;
+                Some(queued) = queued_rx.recv() => {
+                    if sender.send(Message::Text(queued.envelope.clone())).await.is_err() {
+                        break;
+                    }
+                    confirm_queued(&writer_state, queued).await;
+                }
+                msg = rx.recv() => match msg {
+                    Some(msg) => {
+                        if sender.send(msg).await.is_err() {
+                            break;
+                        }
+                    }
+                    None => break,
+                },
             }
         }
     });
     // Do NOT register client until after successful hello handshake and token validation
 
     // Wait for initial hello (must be the first message)
-    // Ephemeral mode: no history is sent and nothing is persisted to disk
-    let hello_msg = match receiver.next().await {
-        Some(Ok(Message::Text(t))) => {
-            match serde_json::from_str::<Hello>(&t) {
-                Ok(h) if h.r#type == "hello" => Some(h),
-                _ => None,
+    let hello = match receiver.next().await {
+        Some(Ok(Message::Text(t))) => match protocol::parse_client_frame(&t) {
+            Ok(ClientFrame::Hello(h)) => h,
+            Ok(_) => {
+                send_frame(&tx, &ServerFrame::error(ErrorCode::HelloRequired, "first frame must be hello"));
+                return;
+            }
+            Err(e) => {
+                send_frame(&tx, &ServerFrame::error(ErrorCode::HelloRequired, e.msg));
+                return;
             }
+        },
+        _ => {
+            send_frame(&tx, &ServerFrame::error(ErrorCode::HelloRequired, "hello required"));
+            return;
         }
-        _ => None,
     };
 
-    let hello = match hello_msg {
-        Some(h) => h,
+    if let Err(e) = hello.validate() {
+        send_frame(&tx, &e.to_frame());
+        return;
+    }
+
+    let version = match protocol::negotiate_version(&hello.versions) {
+        Some(v) => v,
         None => {
-            // close socket with reason
-            let _ = tx.send(Message::Text("{\"type\":\"system\",\"msg\":\"hello required\"}".into()));
+            let msg = format!(
+                "server speaks protocol versions {}-{}",
+                protocol::MIN_PROTOCOL_VERSION,
+                protocol::PROTOCOL_VERSION
+            );
+            send_frame(&tx, &ServerFrame::error(ErrorCode::UnsupportedVersion, msg));
             return;
         }
     };
 
     // validate token → username mapping
-    if let Some(expected_user) = username_for_token(&state.tokens, &hello.token).await {
-        if expected_user != hello.username {
-            let _ = tx.send(Message::Text("{\"type\":\"system\",\"msg\":\"invalid token\"}".into()));
+    if let Some(expected_user) = username_for_token(&state.db, &hello.token).await {
+        if expected_user != crate::auth::normalize_username(&hello.username) {
+            send_frame(&tx, &ServerFrame::error(ErrorCode::InvalidToken, "invalid token"));
             return;
         }
     } else {
-        let _ = tx.send(Message::Text("{\"type\":\"system\",\"msg\":\"unknown token\"}".into()));
+        send_frame(&tx, &ServerFrame::error(ErrorCode::UnknownToken, "unknown token"));
         return;
     }
 
-    // register this client ONLY after successful hello; normalize to lowercase
-    let uname = hello.username.to_lowercase();
-    state.clients.lock().await.insert(uname.clone(), tx.clone());
-    // Broadcast presence after connect
-    broadcast_presence(&state).await;
+    // register this client ONLY after successful hello, under the normalized name
+    let uname = crate::auth::normalize_username(&hello.username);
+    let device_id = hello.device_id.clone().unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    let conn_id = uuid::Uuid::new_v4().to_string();
+    send_frame(&tx, &ServerFrame::Welcome { version, username: uname.clone(), device_id: device_id.clone() });
+    let first_device = {
+        // Hold the clients lock while flushing so nothing live overtakes the backlog
+        let mut clients = state.clients.lock().await;
+        flush_offline_queue(&state, &uname, &device_id, &queued_tx);
+        let devices = clients.entry(uname.clone()).or_default();
+        let first = devices.is_empty();
+        let device = Device { tx: tx.clone(), device_id: device_id.clone(), token: hello.token.clone() };
+        devices.insert(conn_id.clone(), device);
+        first
+    };
+    println!("[ws] '{}' connected device '{}'", uname, device_id);
     // Note: Browser is the source of truth for Kyber keys. Server does not generate or broadcast keys.
 
-    // Notify system join
-    let sys = serde_json::json!({"type":"system","msg": format!("{} connected", uname)});
-    broadcast_json(&state, sys).await;
+    // Presence only changes when the user's first device connects
+    if first_device {
+        broadcast_presence(&state).await;
+        broadcast_frame(&state, &ServerFrame::System { msg: format!("{} connected", uname) }).await;
+    }
 
     // main read loop: forward messages
     while let Some(Ok(msg)) = receiver.next().await {
         match msg {
             Message::Text(txt) => {
                 println!("[ws] Received message: {}", txt);
-                let v: serde_json::Value = match serde_json::from_str(&txt) {
-                    Ok(val) => val,
-                    Err(e) => {
-                        println!("[ws] Invalid message format: {} | Error: {}", txt, e);
-                        let _ = tx.send(Message::Text("{\"type\":\"system\",\"msg\":\"invalid message format\"}".into()));
-                        continue;
-                    }
-                };
-                match v.get("type").and_then(|t| t.as_str()) {
-                    Some("ciphertext") => {
-                        println!("[ws] [E2EE] Received ciphertext message");
-                        let ciphertext = v.get("ciphertext").and_then(|c| c.as_str()).unwrap_or("").to_string();
-                        let nonce = v.get("nonce").and_then(|n| n.as_str()).unwrap_or("").to_string();
-                        let kyber_ct = v.get("kyber_ct").and_then(|k| k.as_str()).unwrap_or("").to_string();
-                        println!("[ws] [E2EE] Raw values:\n  ciphertext: {}\n  nonce: {}\n  kyber_ct: {}", ciphertext, nonce, kyber_ct);
-                        let to = v.get("to").and_then(|t| t.as_str()).map(|s| s.to_string());
-                        let from = v.get("from").and_then(|f| f.as_str()).unwrap_or("").to_string();
-                        println!("[ws] [E2EE] From: {} To: {:?}", from, to);
-                        println!("[ws] [E2EE] Ciphertext: {}\nNonce: {}\nKyber_ct: {}", ciphertext, nonce, kyber_ct);
-                        // Ephemeral mode: do not persist ciphertext to DB
-                        // Route to specific user and echo to sender so they see their own message
-                        if let Some(to) = to {
-                            let clients = state.clients.lock().await;
-                            let mut echoed = false;
-                            if let Some(dest_tx) = clients.get(&to) {
-                                let send_result = dest_tx.send(Message::Text(txt.clone()));
-                                println!("[ws] [E2EE] Sent ciphertext to '{}': {:?}", to, send_result);
-                            }
-                            let from_lc = from.to_lowercase();
-                            if let Some(sender_tx) = clients.get(&from_lc) {
-                                let send_result = sender_tx.send(Message::Text(txt.clone()));
-                                echoed = true;
-                                println!("[ws] [E2EE] Echoed ciphertext back to sender '{}': {:?}", from_lc, send_result);
-                            }
-                            if !echoed {
-                                println!("[ws] [E2EE] Could not echo to sender '{}' (not found)", from_lc);
-                            }
-                        }
-                    }
-                    Some("plaintext") => {
-                        println!("[ws] [PLAINTEXT] Received plaintext message");
-                        if let Ok(f) = serde_json::from_value::<ForwardMsg>(v.clone()) {
-                            let from = f.from.clone();
-                            let to = f.to.clone();
-                            let data = f.data.clone().unwrap_or_default();
-                            println!("[ws] [PLAINTEXT] Fields: from={}, to={:?}, data={}", from, to, data);
-                            // Ephemeral mode: do not persist plaintext
-                            // If `to` present, route to specific user
-                            if let Some(to) = f.to.clone() {
-                                let clients = state.clients.lock().await;
-                                if let Some(dest_tx) = clients.get(&to) {
-                                    let send_result = dest_tx.send(Message::Text(txt.clone()));
-                                    println!("[ws] Sent plaintext to '{}': {:?}", to, send_result);
-                                }
-                                // Echo plaintext back to sender so they see their own message
-                                let from_lc = from.to_lowercase();
-                                if let Some(sender_tx) = clients.get(&from_lc) {
-                                    let _ = sender_tx.send(Message::Text(txt.clone()));
-                                }
-                            }
-                        }
+                match protocol::parse_client_frame(&txt) {
+                    Ok(ClientFrame::Ciphertext(c)) => handle_ciphertext(&state, &uname, &tx, c).await,
+                    Ok(ClientFrame::GroupCiphertext(g)) => handle_group_ciphertext(&state, &uname, &tx, g).await,
+                    Ok(ClientFrame::Plaintext(p)) => handle_plaintext(&state, &uname, &tx, p).await,
+                    Ok(ClientFrame::Read(r)) => handle_read(&state, &uname, &tx, r).await,
+                    Ok(ClientFrame::Hello(_)) => {
+                        send_frame(&tx, &ServerFrame::error(ErrorCode::UnexpectedHello, "already said hello"));
                     }
-                    _ => {
-                        println!("[ws] Unknown message type: {:?}", v.get("type"));
+                    Err(e) => {
+                        println!("[ws] Invalid message: {} | Error: {}", txt, e.msg);
+                        send_frame(&tx, &e.to_frame());
                     }
                 }
             }
             Message::Binary(data) => {
                 println!("[ws] Received binary message: {:?}", data);
+                send_frame(&tx, &ServerFrame::error(ErrorCode::UnsupportedFrame, "binary frames are not supported"));
             }
             Message::Ping(data) => {
                 println!("[ws] Received ping: {:?}", data);
@@ -186,20 +175,615 @@ async fn handle_socket(stream: WebSocket, state: AppState) {
             }
         }
     }
-    // Cleanup on disconnect: remove client and broadcast presence
-    // remove normalized key
-    state.clients.lock().await.remove(&uname);
-    broadcast_presence(&state).await;
+    // Cleanup on disconnect: drop this device; the user goes offline with their last one
+    println!("[ws] '{}' disconnected device '{}'", uname, device_id);
+    if remove_device(&state, &uname, &conn_id).await {
+        broadcast_presence(&state).await;
+    }
+}
+
+/// Forget one of `uname`'s sockets. Returns true if that was the user's last one.
+async fn remove_device(state: &AppState, uname: &str, conn_id: &str) -> bool {
+    let mut clients = state.clients.lock().await;
+    let Some(devices) = clients.get_mut(uname) else {
+        return false;
+    };
+    devices.remove(conn_id);
+    if devices.is_empty() {
+        clients.remove(uname);
+        return true;
+    }
+    false
+}
+
+/// Close every device that said hello with `token` (called on /logout). The
+/// user's other sessions stay connected.
+pub async fn close_session(state: &AppState, uname: &str, token: &str) {
+    let went_offline = {
+        let mut clients = state.clients.lock().await;
+        let Some(devices) = clients.get_mut(uname) else {
+            return;
+        };
+        devices.retain(|_, device| {
+            if device.token != token {
+                return true;
+            }
+            let _ = device.tx.send(Message::Close(None));
+            false
+        });
+        if devices.is_empty() {
+            clients.remove(uname);
+            true
+        } else {
+            false
+        }
+    };
+    if went_offline {
+        broadcast_presence(state).await;
+    }
+}
+
+/// Point devices connected with `old_token` at its replacement after /refresh,
+/// so a later /logout with the new token still finds them
+pub async fn rebind_session(state: &AppState, old_token: &str, new_token: &str) {
+    let mut clients = state.clients.lock().await;
+    for device in clients.values_mut().flat_map(|devices| devices.values_mut()) {
+        if device.token == old_token {
+            device.token = new_token.to_string();
+        }
+    }
+}
+
+/// Send `text` to every connected socket of `user`. Returns true if at least one
+/// socket accepted it.
+fn send_to_user(clients: &HashMap<String, Devices>, user: &str, text: &str) -> bool {
+    send_to_sockets(clients, user, |_| true, text)
+}
+
+fn send_to_sockets(
+    clients: &HashMap<String, Devices>,
+    user: &str,
+    filter: impl Fn(&Device) -> bool,
+    text: &str,
+) -> bool {
+    let Some(devices) = clients.get(user) else {
+        return false;
+    };
+    let mut sent = false;
+    for device in devices.values().filter(|d| filter(d)) {
+        sent |= device.tx.send(Message::Text(text.to_string())).is_ok();
+    }
+    sent
+}
+
+/// `user`'s connected sockets as (device id, sender). Delivery sends through
+/// these after the clients lock is released, so SQLite work never runs under it.
+async fn sockets_of(state: &AppState, user: &str) -> Vec<(String, Tx)> {
+    let clients = state.clients.lock().await;
+    clients
+        .get(user)
+        .map(|devices| devices.values().map(|d| (d.device_id.clone(), d.tx.clone())).collect())
+        .unwrap_or_default()
+}
+
+/// Send `text` to the sockets of `device_id` (or of every device, for None).
+/// Returns true if at least one socket accepted it.
+fn send_to_snapshot(sockets: &[(String, Tx)], device_id: Option<&str>, text: &str) -> bool {
+    let mut sent = false;
+    for (_, tx) in sockets.iter().filter(|(d, _)| device_id.is_none_or(|id| id == d)) {
+        sent |= tx.send(Message::Text(text.to_string())).is_ok();
+    }
+    sent
+}
+
+/// Route an E2EE envelope to its recipient's devices, each getting only its own key
+/// slot (slots for offline devices are queued), send the sender a delivered/failed
+/// receipt, and echo it so they see their own message. `from` is always the
+/// authenticated `uname`, never what the client claimed.
+async fn handle_ciphertext(state: &AppState, uname: &str, tx: &Tx, mut c: Ciphertext) {
+    if let Err(e) = c.validate().and_then(|_| c.stamp_sender(uname)) {
+        send_frame(tx, &e.to_frame());
+        return;
+    }
+    // Every envelope carries a message id so receipts can refer to it
+    let mid = c.mid.get_or_insert_with(|| uuid::Uuid::new_v4().to_string()).clone();
+    let to = crate::auth::normalize_username(&c.to);
+    c.to = to.clone();
+    println!("[ws] [E2EE] From: {} To: {} mid: {} slots: {}", uname, to, mid, c.slots.len());
+    let envelope = ServerFrame::Ciphertext(c.clone()).to_json();
+
+    if !crate::auth::user_exists(&state.db, &to) {
+        println!("[ws] [E2EE] Unknown recipient '{}'", to);
+        send_frame(tx, &failed(&mid, Some(&to), "unknown recipient"));
+    } else {
+        let slots: Vec<&KeySlot> = c.slots.iter().collect();
+        let outcome = deliver_slots(state, &to, &slots, |slot| {
+            ServerFrame::Ciphertext(Ciphertext { slots: vec![slot.clone()], ..c.clone() }).to_json()
+        })
+        .await;
+        if report_delivery(tx, &mid, &to, outcome) {
+            record_route(state, &mid, uname, &to);
+        } else {
+            println!("[ws] [E2EE] No slot matches a device of '{}'", to);
+            send_frame(tx, &failed(&mid, Some(&to), "no slot for any recipient device"));
+        }
+    }
+    let send_result = tx.send(Message::Text(envelope));
+    println!("[ws] [E2EE] Echoed ciphertext back to sender '{}': {:?}", uname, send_result);
+}
+
+/// Fan a group envelope out to the group's current members. Each member device
+/// gets only its own slot; slots for non-members (e.g. someone just kicked) are
+/// dropped. The sender gets a receipt per member and the usual echo.
+async fn handle_group_ciphertext(state: &AppState, uname: &str, tx: &Tx, mut g: GroupCiphertext) {
+    if let Err(e) = g.validate().and_then(|_| g.stamp_sender(uname)) {
+        send_frame(tx, &e.to_frame());
+        return;
+    }
+    let mid = g.mid.get_or_insert_with(|| uuid::Uuid::new_v4().to_string()).clone();
+    let members = groups::members(&state.db, &g.group).unwrap_or_else(|e| {
+        println!("[ws] [GROUP] Member lookup for '{}' failed: {}", g.group, e);
+        Vec::new()
+    });
+    if !members.iter().any(|m| m == uname) {
+        send_frame(tx, &ServerFrame::error(ErrorCode::NotGroupMember, "not a member of that group"));
+        return;
+    }
+    for slot in &mut g.slots {
+        slot.to = slot.to.as_deref().map(crate::auth::normalize_username);
+    }
+    println!("[ws] [GROUP] From: {} Group: {} mid: {} slots: {}", uname, g.group, mid, g.slots.len());
+    let envelope = ServerFrame::GroupCiphertext(g.clone()).to_json();
+
+    let mut reached_any = false;
+    for member in &members {
+        let slots: Vec<&KeySlot> = g.slots.iter().filter(|s| s.to.as_deref() == Some(member.as_str())).collect();
+        if slots.is_empty() {
+            continue;
+        }
+        let outcome = deliver_slots(state, member, &slots, |slot| {
+            ServerFrame::GroupCiphertext(GroupCiphertext { slots: vec![slot.clone()], ..g.clone() }).to_json()
+        })
+        .await;
+        // The sender's own other devices don't need receipts
+        if member == uname {
+            continue;
+        }
+        if report_delivery(tx, &mid, member, outcome) {
+            record_route(state, &mid, uname, member);
+            reached_any = true;
+        }
+    }
+    if !reached_any {
+        println!("[ws] [GROUP] No slot matches a member device of '{}'", g.group);
+        send_frame(tx, &failed(&mid, Some(&g.group), "no slot for any member device"));
+    }
+    let _ = tx.send(Message::Text(envelope));
+}
+
+/// What happened to the slots addressed to one user
+#[derive(Default)]
+struct SlotDelivery {
+    live: bool,
+    queued: bool,
+    queue_error: Option<queue::QueueError>,
+}
+
+/// Hand each slot to the matching connected device of `user`, or queue it if the
+/// device is offline. `frame_for` builds the single-slot envelope for a slot.
+/// Slots for devices that no longer have a live key are dropped rather than
+/// queued forever.
+async fn deliver_slots(
+    state: &AppState,
+    user: &str,
+    slots: &[&KeySlot],
+    frame_for: impl Fn(&KeySlot) -> String,
+) -> SlotDelivery {
+    let known: Vec<String> = match keydir::device_keys(&state.db, user) {
+        Ok(keys) => keys.into_iter().map(|k| k.device_id).collect(),
+        Err(e) => {
+            println!("[ws] Device lookup for '{}' failed: {}", user, e);
+            Vec::new()
+        }
+    };
+    let sockets = sockets_of(state, user).await;
+    let mut outcome = SlotDelivery::default();
+    let mut queued = Vec::new();
+    for slot in slots {
+        let single = frame_for(slot);
+        if send_to_snapshot(&sockets, Some(&slot.device_id), &single) {
+            outcome.live = true;
+        } else if known.contains(&slot.device_id) {
+            match queue::enqueue(&state.db, user, &slot.device_id, &single) {
+                Ok(()) => {
+                    outcome.queued = true;
+                    queued.push((slot.device_id.as_str(), single));
+                }
+                Err(e) => outcome.queue_error = Some(e),
+            }
+        }
+    }
+    // A device that connected after the snapshot may have read its backlog before
+    // the enqueue, so it gets the slot live too. The queued copy goes out again
+    // on its next hello (clients drop duplicates by mid).
+    if !queued.is_empty() {
+        let sockets = sockets_of(state, user).await;
+        for (device_id, single) in &queued {
+            outcome.live |= send_to_snapshot(&sockets, Some(device_id), single);
+        }
+    }
+    outcome
+}
+
+/// Tell the sender what happened to message `mid` for recipient `to`. Returns
+/// false if none of the slots went anywhere (and nothing was reported).
+fn report_delivery(tx: &Tx, mid: &str, to: &str, outcome: SlotDelivery) -> bool {
+    if outcome.live {
+        println!("[ws] [E2EE] Sent ciphertext to '{}'", to);
+        send_frame(tx, &delivered(mid, to));
+    } else if outcome.queued {
+        println!("[ws] [E2EE] '{}' offline, queued ciphertext", to);
+    } else if let Some(e) = outcome.queue_error {
+        println!("[ws] [E2EE] Could not queue ciphertext for '{}': {}", to, e);
+        let reason = match e {
+            queue::QueueError::Full => "recipient queue full",
+            queue::QueueError::Db(_) => "could not queue message",
+        };
+        send_frame(tx, &failed(mid, Some(to), reason));
+    } else {
+        return false;
+    }
+    true
+}
+
+/// Tell a group's members (and `username`, if they just left or were kicked)
+/// about a membership change. Offline members get it when they next connect.
+pub async fn notify_group_changed(state: &AppState, group: &GroupInfo, event: &str, username: &str, by: &str) {
+    let frame = ServerFrame::GroupChanged {
+        group_id: group.id.clone(),
+        name: group.name.clone(),
+        event: event.to_string(),
+        username: username.to_string(),
+        by: by.to_string(),
+        owner: group.owner.clone(),
+        members: group.member_names(),
+        ts: crate::db::unix_now(),
+    }
+    .to_json();
+    let mut recipients = group.member_names();
+    if !recipients.iter().any(|m| m == username) {
+        recipients.push(username.to_string());
+    }
+    for member in &recipients {
+        deliver_or_queue(state, member, frame.clone()).await;
+    }
+    println!("[ws] [GROUP] {} '{}' {} (by {})", group.id, username, event, by);
+}
+
+/// Unencrypted message: route to `to` (if given) and echo back to the sender
+async fn handle_plaintext(state: &AppState, uname: &str, tx: &Tx, mut p: Plaintext) {
+    if let Err(e) = p.validate().and_then(|_| p.stamp_sender(uname)) {
+        send_frame(tx, &e.to_frame());
+        return;
+    }
+    println!("[ws] [PLAINTEXT] Fields: from={}, to={:?}, data={}", uname, p.to, p.data);
+    // Ephemeral mode: do not persist plaintext
+    let to = crate::auth::normalize_username(p.to.as_deref().unwrap_or_default());
+    let frame = ServerFrame::Plaintext(p).to_json();
+    let sent = send_to_user(&*state.clients.lock().await, &to, &frame);
+    println!("[ws] Sent plaintext to '{}': {}", to, sent);
+    // Echo plaintext back to sender so they see their own message
+    let _ = tx.send(Message::Text(frame));
+}
+
+/// Note who message `mid` went to, so only they can ack it
+fn record_route(state: &AppState, mid: &str, sender: &str, recipient: &str) {
+    if let Err(e) = queue::record_route(&state.db, mid, sender, recipient) {
+        println!("[ws] Could not record route of {} to '{}': {}", mid, recipient, e);
+    }
+}
+
+/// Read ack from the recipient's client: relay to the original sender, but only
+/// if `to` really sent `uname` that message
+async fn handle_read(state: &AppState, uname: &str, tx: &Tx, r: ReadAck) {
+    if let Err(e) = r.validate() {
+        send_frame(tx, &e.to_frame());
+        return;
+    }
+    let to = crate::auth::normalize_username(&r.to);
+    match queue::take_route(&state.db, &r.mid, &to, uname) {
+        Ok(true) => {}
+        Ok(false) => {
+            println!("[ws] Dropped read ack from '{}' for {} (not sent to them by '{}')", uname, r.mid, to);
+            return;
+        }
+        Err(e) => {
+            println!("[ws] Route lookup for {} failed: {}", r.mid, e);
+            return;
+        }
+    }
+    let ack = ServerFrame::Read { mid: r.mid, from: uname.to_string(), ts: crate::db::unix_now() };
+    deliver_or_queue(state, &to, ack.to_json()).await;
+}
+
+fn delivered(mid: &str, to: &str) -> ServerFrame {
+    ServerFrame::Delivered { mid: mid.to_string(), to: to.to_string(), ts: crate::db::unix_now() }
+}
+
+fn failed(mid: &str, to: Option<&str>, reason: &str) -> ServerFrame {
+    ServerFrame::Failed {
+        mid: mid.to_string(),
+        to: to.map(str::to_string),
+        reason: reason.to_string(),
+        ts: crate::db::unix_now(),
+    }
+}
+
+/// Send `text` to `user` if they're online, otherwise queue it for their next
+/// hello. Returns false if it could do neither.
+async fn deliver_or_queue(state: &AppState, user: &str, text: String) -> bool {
+    if send_to_snapshot(&sockets_of(state, user).await, None, &text) {
+        return true;
+    }
+    if !crate::auth::user_exists(&state.db, user) {
+        return false;
+    }
+    match queue::enqueue(&state.db, user, "", &text) {
+        Ok(()) => {
+            // Same late-connect case as in `deliver_slots`
+            send_to_snapshot(&sockets_of(state, user).await, None, &text);
+            true
+        }
+        Err(e) => {
+            println!("[ws] Could not queue frame for '{}': {}", user, e);
+            false
+        }
+    }
+}
+
+/// An envelope from the offline queue on its way to a socket
+struct QueuedFrame {
+    id: i64,
+    envelope: String,
+    recipient: String,
+}
+
+/// Hand every queued envelope for `uname`'s device `device_id` to its new socket's
+/// writer, oldest first. Nothing is removed here: see `confirm_queued`.
+fn flush_offline_queue(state: &AppState, uname: &str, device_id: &str, queued_tx: &QueuedTx) {
+    let pending = match queue::pending(&state.db, uname, device_id) {
+        Ok(p) => p,
+        Err(e) => {
+            println!("[ws] Could not read offline queue for '{}': {}", uname, e);
+            return;
+        }
+    };
+    if pending.is_empty() {
+        return;
+    }
+    println!("[ws] Delivering {} queued message(s) to '{}'", pending.len(), uname);
+    for (id, envelope) in pending {
+        if queued_tx.send(QueuedFrame { id, envelope, recipient: uname.to_string() }).is_err() {
+            break;
+        }
+    }
+}
+
+/// Called by the socket's writer once a queued envelope was written to the
+/// socket: only now is it removed from the queue, and queued ciphertexts get a
+/// "delivered" receipt back to their sender. If the socket dies first the row
+/// stays and goes out again on the next hello, so delivery is at-least-once
+/// (clients drop duplicates by mid).
+async fn confirm_queued(state: &AppState, queued: QueuedFrame) {
+    if let Err(e) = queue::remove(&state.db, queued.id) {
+        println!("[ws] Could not drop delivered envelope {}: {}", queued.id, e);
+    }
+    let sender_and_mid = match serde_json::from_str::<ServerFrame>(&queued.envelope) {
+        Ok(ServerFrame::Ciphertext(c)) => c.from.zip(c.mid),
+        Ok(ServerFrame::GroupCiphertext(g)) => g.from.zip(g.mid),
+        _ => None,
+    };
+    if let Some((from, mid)) = sender_and_mid {
+        let from = crate::auth::normalize_username(&from);
+        deliver_or_queue(state, &from, delivered(&mid, &queued.recipient).to_json()).await;
+    }
 }
 
 // Broadcast the list of currently online users to all clients
-async fn broadcast_presence(state: &AppState) {
+pub async fn broadcast_presence(state: &AppState) {
     let clients = state.clients.lock().await;
     let online: Vec<String> = clients.keys().cloned().collect();
     drop(clients);
-    let msg = serde_json::json!({
-        "type": "presence",
-        "online": online,
-    });
-    broadcast_json(state, msg).await;
+    broadcast_frame(state, &ServerFrame::Presence { online }).await;
+}
+
+/// Tell every client that the current key of one of `username`'s devices changed
+/// (new key published or current key revoked). Clients verify `sig` against
+/// /server-key; the signed statement is
+/// "noid-key-changed-v2|username|device_id|old_fp|new_fp|key_id|ts" with empty
+/// strings for missing values.
+pub async fn broadcast_key_changed(
+    state: &AppState,
+    username: &str,
+    device_id: &str,
+    old: Option<&KeyRecord>,
+    new: Option<&KeyRecord>,
+) {
+    let old_fp = old.map(|k| k.fingerprint.as_str()).unwrap_or("");
+    let new_fp = new.map(|k| k.fingerprint.as_str()).unwrap_or("");
+    let key_id = new.map(|k| k.key_id.as_str()).unwrap_or("");
+    let ts = crate::db::unix_now();
+    let statement = format!(
+        "noid-key-changed-v2|{}|{}|{}|{}|{}|{}",
+        username, device_id, old_fp, new_fp, key_id, ts
+    );
+    let sig = crate::signing::sign_b64(&state.server_key, statement.as_bytes());
+    let frame = ServerFrame::KeyChanged {
+        username: username.to_string(),
+        device_id: device_id.to_string(),
+        key_id: new.map(|k| k.key_id.clone()),
+        pubkey: new.map(|k| k.pubkey.clone()),
+        kem: new.map(|k| k.kem),
+        old_fingerprint: old.map(|k| k.fingerprint.clone()),
+        new_fingerprint: new.map(|k| k.fingerprint.clone()),
+        ts,
+        sig,
+    };
+    println!("[ws] key_changed user='{}' device='{}' {} -> {}", username, device_id, old_fp, new_fp);
+    broadcast_frame(state, &frame).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::Engine;
+    use crate::crypto::KemVersion;
+
+    fn test_state(users: &[&str]) -> AppState {
+        let db = crate::db::open(":memory:").unwrap();
+        for name in users {
+            db.lock()
+                .unwrap()
+                .execute("INSERT INTO users (username, password_hash, created_at) VALUES (?1, 'x', 0)", rusqlite::params![name])
+                .unwrap();
+        }
+        let server_key = crate::signing::load_or_create(&db).unwrap();
+        let ipfs = crate::ipfs::IpfsClient::new(crate::ipfs::IpfsConfig::default()).unwrap();
+        AppState::new(db, server_key, ipfs)
+    }
+
+    /// Register a fake socket for `user`'s device and return what it receives
+    async fn connect(state: &AppState, user: &str, device_id: &str) -> (Tx, tokio::sync::mpsc::UnboundedReceiver<Message>) {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let device = Device { tx: tx.clone(), device_id: device_id.to_string(), token: String::new() };
+        state.clients.lock().await.entry(user.to_string()).or_default().insert(uuid::Uuid::new_v4().to_string(), device);
+        (tx, rx)
+    }
+
+    fn frames(rx: &mut tokio::sync::mpsc::UnboundedReceiver<Message>) -> Vec<ServerFrame> {
+        std::iter::from_fn(|| rx.try_recv().ok())
+            .filter_map(|msg| match msg {
+                Message::Text(t) => serde_json::from_str(&t).ok(),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn publish_device(state: &AppState, user: &str, device_id: &str) {
+        let key = keydir::NewKey { pubkey_b64: "AAAA", raw_key: device_id.as_bytes(), kem: 1, x25519_b64: None, sig_b64: None };
+        keydir::publish_key(&state.db, user, device_id, &key).unwrap();
+    }
+
+    fn slot(to: Option<&str>, device_id: &str) -> KeySlot {
+        let b64 = |len| base64::engine::general_purpose::STANDARD.encode(vec![0u8; len]);
+        KeySlot {
+            to: to.map(str::to_string),
+            device_id: device_id.to_string(),
+            key_id: None,
+            kem: KemVersion::Kyber1024 as u8,
+            x25519_epk: None,
+            kyber_ct: b64(KemVersion::Kyber1024.ciphertext_bytes()),
+            wrapped_key: b64(48),
+        }
+    }
+
+    fn ciphertext(to: &str, mid: &str, device_id: &str) -> Ciphertext {
+        Ciphertext {
+            from: None,
+            to: to.to_string(),
+            mid: Some(mid.to_string()),
+            nonce: "AAAAAAAAAAAAAAAA".to_string(),
+            ciphertext: "AAAA".to_string(),
+            slots: vec![slot(None, device_id)],
+        }
+    }
+
+    fn read(mid: &str, to: &str) -> ReadAck {
+        ReadAck { mid: mid.to_string(), to: to.to_string() }
+    }
+
+    #[tokio::test]
+    async fn queued_envelopes_stay_queued_until_written() {
+        let state = test_state(&["alice", "bob"]);
+        publish_device(&state, "bob", "phone");
+        let (alice_tx, mut alice_rx) = connect(&state, "alice", "laptop").await;
+        handle_ciphertext(&state, "alice", &alice_tx, ciphertext("bob", "m1", "phone")).await;
+        assert!(!frames(&mut alice_rx).iter().any(|f| matches!(f, ServerFrame::Delivered { .. })));
+
+        let (queued_tx, mut queued_rx) = tokio::sync::mpsc::unbounded_channel();
+        flush_offline_queue(&state, "bob", "phone", &queued_tx);
+        let queued = queued_rx.try_recv().unwrap();
+        // Handed to the writer but not written yet: still there for the next hello
+        assert_eq!(queue::pending(&state.db, "bob", "phone").unwrap().len(), 1);
+
+        confirm_queued(&state, queued).await;
+        assert!(queue::pending(&state.db, "bob", "phone").unwrap().is_empty());
+        let receipts = frames(&mut alice_rx);
+        assert!(matches!(receipts.as_slice(), [ServerFrame::Delivered { mid, to, .. }] if mid == "m1" && to == "bob"));
+    }
+
+    #[tokio::test]
+    async fn read_acks_only_come_from_recipients() {
+        let state = test_state(&["alice", "bob", "mallory"]);
+        let (alice_tx, mut alice_rx) = connect(&state, "alice", "laptop").await;
+        let (bob_tx, _bob_rx) = connect(&state, "bob", "phone").await;
+        let (mallory_tx, _mallory_rx) = connect(&state, "mallory", "pc").await;
+        handle_ciphertext(&state, "alice", &alice_tx, ciphertext("bob", "m1", "phone")).await;
+        frames(&mut alice_rx);
+
+        handle_read(&state, "mallory", &mallory_tx, read("m1", "alice")).await;
+        handle_read(&state, "bob", &bob_tx, read("m2", "alice")).await;
+        assert!(frames(&mut alice_rx).is_empty());
+
+        handle_read(&state, "bob", &bob_tx, read("m1", "Alice")).await;
+        handle_read(&state, "bob", &bob_tx, read("m1", "alice")).await;
+        let acks = frames(&mut alice_rx);
+        assert!(matches!(acks.as_slice(), [ServerFrame::Read { mid, from, .. }] if mid == "m1" && from == "bob"));
+    }
+
+    #[tokio::test]
+    async fn group_envelopes_need_membership() {
+        let state = test_state(&["alice", "bob", "mallory"]);
+        let group = groups::create_group(&state.db, "alice", "team", &["bob".to_string()]).unwrap();
+        let (mallory_tx, mut mallory_rx) = connect(&state, "mallory", "pc").await;
+        let (alice_tx, mut alice_rx) = connect(&state, "alice", "laptop").await;
+        let (_bob_tx, mut bob_rx) = connect(&state, "bob", "phone").await;
+        let envelope = |mid: &str| GroupCiphertext {
+            from: None,
+            group: group.id.clone(),
+            mid: Some(mid.to_string()),
+            nonce: "AAAAAAAAAAAAAAAA".to_string(),
+            ciphertext: "AAAA".to_string(),
+            slots: vec![slot(Some("bob"), "phone"), slot(Some("mallory"), "pc")],
+        };
+
+        handle_group_ciphertext(&state, "mallory", &mallory_tx, envelope("g1")).await;
+        let errors = frames(&mut mallory_rx);
+        assert!(matches!(errors.as_slice(), [ServerFrame::Error { code: ErrorCode::NotGroupMember, .. }]));
+        assert!(frames(&mut bob_rx).is_empty());
+
+        // A member's envelope reaches members only; the non-member's slot is dropped
+        handle_group_ciphertext(&state, "alice", &alice_tx, envelope("g2")).await;
+        assert!(matches!(frames(&mut bob_rx).as_slice(), [ServerFrame::GroupCiphertext(g)] if g.slots.len() == 1));
+        assert!(frames(&mut mallory_rx).is_empty());
+        assert!(frames(&mut alice_rx).iter().any(|f| matches!(f, ServerFrame::Delivered { to, .. } if to == "bob")));
+    }
+
+    #[tokio::test]
+    async fn plaintext_without_a_recipient_gets_an_error() {
+        let state = test_state(&["alice", "bob"]);
+        let (alice_tx, mut alice_rx) = connect(&state, "alice", "laptop").await;
+        let (_bob_tx, mut bob_rx) = connect(&state, "bob", "phone").await;
+        let plaintext = |to: Option<&str>| Plaintext { from: None, to: to.map(str::to_string), data: "hi".to_string() };
+
+        for to in [None, Some(" ")] {
+            handle_plaintext(&state, "alice", &alice_tx, plaintext(to)).await;
+            let errors = frames(&mut alice_rx);
+            assert!(matches!(errors.as_slice(), [ServerFrame::Error { code: ErrorCode::InvalidField, .. }]), "{:?}", to);
+        }
+
+        handle_plaintext(&state, "alice", &alice_tx, plaintext(Some("Bob"))).await;
+        assert!(matches!(frames(&mut bob_rx).as_slice(), [ServerFrame::Plaintext(p)] if p.from.as_deref() == Some("alice")));
+        assert!(matches!(frames(&mut alice_rx).as_slice(), [ServerFrame::Plaintext(_)]));
+    }
 }